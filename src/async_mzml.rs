@@ -0,0 +1,84 @@
+/*!An async LazyMzML on tokio, enabled with the `tokio` feature.
+
+Opening parses the index and scan metadata synchronously, as `LazyMzML::new` does. Spectrum data is
+then fetched with `LazyMzML::fetch_scan_data` on tokio's blocking thread pool, so fetching never
+blocks the runtime. `tokio::fs::File` runs its reads on that same pool, but only through its one
+seek cursor, which serializes every fetch; reading at each spectrum's offset instead lets any
+number of fetches run at once, on every target.
+*/
+use crate::{LazyMzML, MzMLParseError, ScanWithData, ScanWithoutData};
+use futures::stream::{self, Stream, StreamExt};
+use std::sync::Arc;
+
+///A LazyMzML whose spectrum data is loaded without blocking the async runtime, by concurrent fetches if wanted.
+#[derive(Debug, Clone)]
+pub struct AsyncLazyMzML {
+    mzml: Arc<LazyMzML>,
+}
+impl AsyncLazyMzML {
+    ///Create a new AsyncLazyMzML from an indexed mzml file, parsing its index synchronously.
+    pub fn new(mzml_file: std::fs::File) -> Result<Self, MzMLParseError> {
+        Ok(AsyncLazyMzML {
+            mzml: Arc::new(LazyMzML::new(mzml_file)?),
+        })
+    }
+    ///Return the synchronous LazyMzML holding the metadata, for everything that doesn't read spectrum data.
+    pub fn lazy_mzml(&self) -> &LazyMzML {
+        &self.mzml
+    }
+    ///Return an iterator of the scans contained in the file.
+    pub fn iter_scan(&self) -> impl Iterator<Item = &ScanWithoutData> {
+        self.mzml.iter_scan()
+    }
+    ///Load the data of a scan from disk.
+    pub async fn fetch_scan_data(
+        &self,
+        scan: &ScanWithoutData,
+    ) -> Result<ScanWithData, MzMLParseError> {
+        let mzml = Arc::clone(&self.mzml);
+        let scan = scan.clone();
+        tokio::task::spawn_blocking(move || mzml.fetch_scan_data(&scan))
+            .await
+            .map_err(std::io::Error::from)?
+    }
+    /**Return a stream of every spectrum in the file, the data loaded as the stream is polled.
+    Spectra are fetched one at a time, in file order. Spectra whose data can't be retrieved are yielded as errors.
+    */
+    pub fn stream_spectrum(&self) -> impl Stream<Item = Result<ScanWithData, MzMLParseError>> + '_ {
+        stream::iter(self.iter_scan()).then(move |scan| self.fetch_scan_data(scan))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: &str = "test_data/small.pwiz.1.1.mzML";
+
+    #[tokio::test]
+    async fn async_fetch() {
+        let mzml = AsyncLazyMzML::new(std::fs::File::open(SMALL).unwrap()).unwrap();
+        let spectra: Vec<_> = mzml.stream_spectrum().collect().await;
+        assert_eq!(spectra.len(), 48);
+        for (spectrum, fetched) in spectra.into_iter().zip(mzml.lazy_mzml().iter_spectrum()) {
+            assert_eq!(spectrum.unwrap(), fetched.unwrap());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_fetch() {
+        let mzml = AsyncLazyMzML::new(std::fs::File::open(SMALL).unwrap()).unwrap();
+        //Issued last to first, so reads far apart in the file are in flight together.
+        let scans: Vec<_> = mzml.iter_scan().collect();
+        let fetches = scans.iter().rev().map(|scan| mzml.fetch_scan_data(scan));
+        let spectra = futures::future::join_all(fetches).await;
+        assert_eq!(spectra.len(), 48);
+        for (spectrum, fetched) in spectra
+            .into_iter()
+            .rev()
+            .zip(mzml.lazy_mzml().iter_spectrum())
+        {
+            assert_eq!(spectrum.unwrap(), fetched.unwrap());
+        }
+    }
+}
@@ -0,0 +1,176 @@
+/*!Building indexed mzML from scratch.
+
+`MzMLBuilder` collects spectra, typically made with `ScanWithData::new`, and writes them as a
+complete indexed mzML document. The header is generated: the PSI-MS and unit cv lists, a file
+description of the spectrum types present, and the software, instrument configuration and data
+processing the spectra refer to.
+*/
+use crate::mass_spectrum::MassScan;
+use crate::writer::IndexedWriter;
+use crate::{list_open_tag, MzMLParseError, ScanWithData};
+use quick_xml::escape::escape;
+use std::io::Write;
+
+const SOFTWARE_ID: &str = "mzml";
+const INSTRUMENT_CONFIGURATION_ID: &str = "IC1";
+const DATA_PROCESSING_ID: &str = "mzml_writer";
+
+///Collects spectra for a new mzML file, see `ScanWithData::new`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MzMLBuilder {
+    id: String,
+    spectra: Vec<ScanWithData>,
+}
+impl MzMLBuilder {
+    ///Create an empty builder for a run with the given id.
+    pub fn new(id: &str) -> Self {
+        MzMLBuilder {
+            id: id.to_string(),
+            spectra: Vec::new(),
+        }
+    }
+    ///Add a spectrum to the end of the run. Indices and list counts are set when the file is written.
+    pub fn add_spectrum(&mut self, spectrum: ScanWithData) -> &mut Self {
+        self.spectra.push(spectrum);
+        self
+    }
+    ///Return the spectra added so far.
+    pub fn spectra(&self) -> &[ScanWithData] {
+        &self.spectra
+    }
+    /**Write the spectra as indexed mzML, with a generated header, an index and a checksum.
+    The file holds no chromatograms.
+     */
+    pub fn write_indexed<W: Write>(&self, writer: W) -> Result<(), MzMLParseError> {
+        let mut writer = IndexedWriter::new(writer);
+        writer.write_raw(&self.header())?;
+        writer.write_raw(&list_open_tag(
+            "spectrumList",
+            self.spectra.len(),
+            &Some(DATA_PROCESSING_ID.to_string()),
+        ))?;
+        for (index, spectrum) in self.spectra.iter().enumerate() {
+            let mut spectrum = spectrum.clone();
+            spectrum.index = index;
+            spectrum.update_counts();
+            writer.write_spectrum(&spectrum)?;
+        }
+        writer.write_raw("</spectrumList>\n")?;
+        writer.write_raw(&list_open_tag(
+            "chromatogramList",
+            0,
+            &Some(DATA_PROCESSING_ID.to_string()),
+        ))?;
+        writer.write_raw("</chromatogramList>\n</run>\n</mzML>\n")?;
+        writer.finish()?;
+        Ok(())
+    }
+    ///Return the text of the file before the spectrum list, up to and including the run open tag.
+    fn header(&self) -> String {
+        let mut file_content = String::new();
+        for (accession, name, ms1) in [
+            ("MS:1000579", "MS1 spectrum", true),
+            ("MS:1000580", "MSn spectrum", false),
+        ] {
+            if self
+                .spectra
+                .iter()
+                .any(|s| s.ms_level().is_some_and(|level| (level == 1) == ms1))
+            {
+                file_content.push_str(&format!(
+                    "<cvParam cvRef=\"MS\" accession=\"{}\" name=\"{}\" value=\"\"/>\n",
+                    accession, name
+                ));
+            }
+        }
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<indexedmzML xmlns="http://psi.hupo.org/ms/mzml" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://psi.hupo.org/ms/mzml http://psidev.info/files/ms/mzML/xsd/mzML1.1.2_idx.xsd">
+<mzML xmlns="http://psi.hupo.org/ms/mzml" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://psi.hupo.org/ms/mzml http://psidev.info/files/ms/mzML/xsd/mzML1.1.0.xsd" id="{id}" version="1.1.0">
+<cvList count="2">
+<cv id="MS" fullName="Proteomics Standards Initiative Mass Spectrometry Ontology" URI="https://raw.githubusercontent.com/HUPO-PSI/psi-ms-CV/master/psi-ms.obo"/>
+<cv id="UO" fullName="Unit Ontology" URI="http://ontologies.berkeleybop.org/uo.obo"/>
+</cvList>
+<fileDescription>
+<fileContent>
+{file_content}</fileContent>
+</fileDescription>
+<softwareList count="1">
+<software id="{software}" version="{version}">
+<cvParam cvRef="MS" accession="MS:1000799" name="custom unreleased software tool" value="{software}"/>
+</software>
+</softwareList>
+<instrumentConfigurationList count="1">
+<instrumentConfiguration id="{instrument}">
+<cvParam cvRef="MS" accession="MS:1000031" name="instrument model" value=""/>
+</instrumentConfiguration>
+</instrumentConfigurationList>
+<dataProcessingList count="1">
+<dataProcessing id="{processing}">
+<processingMethod order="0" softwareRef="{software}">
+<cvParam cvRef="MS" accession="MS:1000544" name="Conversion to mzML" value=""/>
+</processingMethod>
+</dataProcessing>
+</dataProcessingList>
+<run id="{id}" defaultInstrumentConfigurationRef="{instrument}">
+"#,
+            id = escape(&self.id),
+            file_content = file_content,
+            software = SOFTWARE_ID,
+            version = env!("CARGO_PKG_VERSION"),
+            instrument = INSTRUMENT_CONFIGURATION_ID,
+            processing = DATA_PROCESSING_ID,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mass_spectrum::MassSpectrum;
+    use crate::{validate_file, LazyMzML};
+    use std::io::Write;
+    use uom::si::f32::Time;
+    use uom::si::time::minute;
+
+    #[test]
+    fn build_and_read_back() {
+        let ms1_peaks = [(100.5, 10.0), (200.25, 20.0), (300.125, 5.0)];
+        let ms2_peaks = [(150.0, 1.0), (250.0, 2.0)];
+        let mut builder = MzMLBuilder::new("simulated");
+        builder
+            .add_spectrum(ScanWithData::new(
+                String::from("scan=1"),
+                &ms1_peaks,
+                1,
+                Time::new::<minute>(1.5),
+            ))
+            .add_spectrum(
+                ScanWithData::new(
+                    String::from("scan=2"),
+                    &ms2_peaks,
+                    2,
+                    Time::new::<minute>(1.6),
+                )
+                .with_precursor(200.25, Some(2)),
+            );
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        builder.write_indexed(&mut file).unwrap();
+        file.flush().unwrap();
+
+        let report = validate_file(file.path()).unwrap();
+        assert!(report.is_valid(), "{:?}", report.problems);
+        let mzml = LazyMzML::new(file.reopen().unwrap()).unwrap();
+        assert_eq!(mzml.spectrum_count(), 2);
+        let spectra: Vec<ScanWithData> = mzml.iter_spectrum().map(|s| s.unwrap()).collect();
+        assert_eq!(spectra[0].peaks().unwrap(), ms1_peaks.to_vec());
+        assert_eq!(spectra[0].ms_level(), Some(1));
+        assert_eq!(spectra[0].rt(), Some(Time::new::<minute>(1.5)));
+        assert_eq!(spectra[1].peaks().unwrap(), ms2_peaks.to_vec());
+        assert_eq!(spectra[1].scan_number(), Some(2));
+        let precursor = &spectra[1].precursor_list.as_ref().unwrap().precursors[0];
+        assert_eq!(precursor.selected_ion_mz(), Some(200.25));
+        assert_eq!(precursor.charge_state(), Some(2));
+        assert_eq!(precursor.isolation_window.target_mz(), Some(200.25));
+    }
+}
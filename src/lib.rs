@@ -6,17 +6,50 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use thiserror::Error;
 use uom::si::f32::Time;
 use uom::si::time::{minute, second};
 use zune_inflate::DeflateDecoder;
 
+#[cfg(feature = "tokio")]
+mod async_mzml;
+mod builder;
 pub mod mass_spectrum;
-use mass_spectrum::{ControlledVocabularyParameter, MassScan, MassSpectrum};
+mod numpress;
+mod stream;
+mod validate;
+mod writer;
+#[cfg(feature = "tokio")]
+pub use async_mzml::AsyncLazyMzML;
+pub use builder::MzMLBuilder;
+use mass_spectrum::{
+    ControlledVocabularyParameter, MassScan, MassSpectrum, Polarity, SimpleSpectrum, SpectrumMode,
+    Tolerance, UserParameter,
+};
+use numpress::Numpress;
+use sha1::{Digest, Sha1};
+pub use stream::stream_spectra;
+pub use validate::{validate_file, ValidationProblem, ValidationReport};
+use writer::IndexedWriter;
 
-fn base64_decode(data: String) -> Result<Vec<u8>, MzMLParseError> {
-    Ok(general_purpose::STANDARD.decode(data)?)
+/**Decode a base64 binary, tolerating the embedded whitespace and missing padding some converters write.
+The text is only copied when it holds whitespace to strip.
+*/
+fn base64_decode(data: &str) -> Result<Vec<u8>, MzMLParseError> {
+    let stripped: String;
+    let data = if data.bytes().any(|byte| byte.is_ascii_whitespace()) {
+        stripped = data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        &stripped
+    } else {
+        data
+    };
+    match general_purpose::STANDARD.decode(data.as_bytes()) {
+        Ok(binary) => Ok(binary),
+        Err(error) => general_purpose::STANDARD_NO_PAD
+            .decode(data.as_bytes())
+            .map_err(|_| error.into()),
+    }
 }
 /**A structure holding the scan information of an Inbdexed mzml file.
 Spectrum data will be loaded lazily from disk when objects bearing the MassSpectrum trait are retreived.
@@ -24,26 +57,66 @@ Spectrum data will be loaded lazily from disk when objects bearing the MassSpect
 #[derive(Debug)]
 pub struct LazyMzML {
     mzml_struct: IndexedMzML,
-    file: File,
-    scan_offsets: HashMap<String, usize>,
-    chromatogram_offsets: HashMap<String, usize>,
+    file: SharedFile,
+    ///When set, spectra are sliced out of this map of `file` instead of read from it.
+    mmap: Option<memmap2::Mmap>,
+    scan_offsets: HashMap<String, u64>,
+    chromatogram_offsets: HashMap<String, u64>,
+    ///(retention time, position in the spectrum list) of every scan with a retention time, sorted by time.
+    rt_index: Vec<(Time, usize)>,
 }
 impl LazyMzML {
-    ///Create a new LazyMzML from an indexed mzml file.
+    ///Create a new LazyMzML from an indexed mzml file, read from its start whatever the file cursor.
     pub fn new(mzml_file: File) -> Result<Self, MzMLParseError> {
-        let buffreader = BufReader::new(&mzml_file);
-        let mzml: IndexedMzML = from_reader(buffreader)?;
-        let mut scan_offsets: HashMap<String, usize> = HashMap::new();
+        let mzml_file = SharedFile::new(mzml_file);
+        let buffreader = BufReader::new(PositionedReader::new(&mzml_file, 0));
+        let mut mzml: IndexedMzML = from_reader(buffreader)?;
+        mzml.mzml.run.spectrum_list.assign_missing_indices();
+        let param_groups = &mzml.mzml.referenceable_param_group_list;
+        for spectrum in mzml.mzml.run.spectrum_list.spectra.iter_mut() {
+            spectrum.resolve_param_groups(param_groups);
+        }
+        for configuration in mzml
+            .mzml
+            .instrument_configuration_list
+            .configurations
+            .iter_mut()
+        {
+            param_groups.expand(
+                &mut configuration.referenceable_param_group_ref,
+                &mut configuration.cv_param,
+            );
+        }
+        for source_file in mzml
+            .mzml
+            .file_description
+            .source_file_list
+            .iter_mut()
+            .flat_map(|list| list.source_files.iter_mut())
+        {
+            param_groups.expand(
+                &mut source_file.referenceable_param_group_ref,
+                &mut source_file.cv_param,
+            );
+        }
+        let mut rt_index: Vec<(Time, usize)> = mzml
+            .mzml
+            .run
+            .spectrum_list
+            .spectra
+            .iter()
+            .enumerate()
+            .filter_map(|(position, s)| Some((s.rt()?, position)))
+            .collect();
+        rt_index.sort_by(|a, b| a.0.value.total_cmp(&b.0.value));
         let temp_index_list: IndexList;
         let index_list = match &mzml.index_list {
             Some(i) => i,
             None => {
                 if let Some(index) = &mzml.index {
-                    let mut vec = Vec::new();
-                    vec.push(index.to_owned());
                     temp_index_list = IndexList {
                         count: 1,
-                        indexs: vec,
+                        indexs: vec![index.to_owned()],
                     };
                     &temp_index_list
                 } else {
@@ -55,83 +128,661 @@ impl LazyMzML {
                 }
             }
         };
-        index_list
-            .indexs
-            .iter()
-            .find(|index| index.name == "spectrum")
-            .expect("All indexed mzML should have a spectrum index")
-            .offsets
-            .iter()
-            .for_each(|offset| {
-                scan_offsets.insert(offset.id_ref.clone(), offset.offset);
-            });
-        let mut chromatogram_offsets: HashMap<String, usize> = HashMap::new();
-        index_list
-            .indexs
-            .iter()
-            .find(|index| index.name == "chromatogram")
-            .expect("All indexed mzML should have a chromatogram index")
-            .offsets
-            .iter()
-            .for_each(|offset| {
-                chromatogram_offsets.insert(offset.id_ref.clone(), offset.offset);
-            });
+        //A missing index is left empty, fetching its elements then fails with `MissingOffset`.
+        let offsets = |name: &str| -> HashMap<String, u64> {
+            index_list
+                .indexs
+                .iter()
+                .find(|index| index.name == name)
+                .map(|index| {
+                    index
+                        .offsets
+                        .iter()
+                        .map(|offset| (offset.id_ref.clone(), offset.offset))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let scan_offsets = offsets("spectrum");
+        let chromatogram_offsets = offsets("chromatogram");
         Ok(LazyMzML {
             mzml_struct: mzml,
             file: mzml_file,
-            scan_offsets: scan_offsets,
-            chromatogram_offsets: chromatogram_offsets,
+            mmap: None,
+            scan_offsets,
+            chromatogram_offsets,
+            rt_index,
         })
     }
+    /**As `new`, but also accepting a gzip compressed file (`.mzML.gz`).
+    Compressed input is detected by its magic bytes and streamed into an anonymous temporary file,
+    which the index offsets then refer to. Uncompressed input is opened as with `new`.
+    */
+    pub fn new_auto_decompress(mzml_file: File) -> Result<Self, MzMLParseError> {
+        const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+        let mut magic = [0_u8; 2];
+        let mzml_file = SharedFile::new(mzml_file);
+        let mut reader = PositionedReader::new(&mzml_file, 0);
+        if reader.read_exact(&mut magic).is_err() || magic != GZIP_MAGIC {
+            return LazyMzML::new(mzml_file.into_inner());
+        }
+        let mut decoder = flate2::read::MultiGzDecoder::new(PositionedReader::new(&mzml_file, 0));
+        let mut file = tempfile::tempfile()?;
+        std::io::copy(&mut decoder, &mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+        LazyMzML::new(file)
+    }
+    /**Open the indexed mzML file at `path` memory mapped.
+    Fetching a spectrum then reads straight from the map, with no system call per lookup.
+    The file must not be modified while the LazyMzML is alive; use `new` for files that may change.
+    */
+    pub fn open_mmap<P: AsRef<std::path::Path>>(path: P) -> Result<Self, MzMLParseError> {
+        let file = File::open(path)?;
+        //Safety: the map is read only, and the caller is told not to modify the file while it is mapped.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let mut mzml = LazyMzML::new(file)?;
+        mzml.mmap = Some(mmap);
+        Ok(mzml)
+    }
+    ///As `new`, but returning an error if the file does not match its `<fileChecksum>`.
+    pub fn open_verified(mzml_file: File) -> Result<Self, MzMLParseError> {
+        let mzml = LazyMzML::new(mzml_file)?;
+        if !mzml.verify_checksum()? {
+            return Err(MzMLParseError::ChecksumMismatch(
+                mzml.mzml_struct.file_checksum.clone(),
+            ));
+        }
+        Ok(mzml)
+    }
+    /**Check the file against its `<fileChecksum>`.
+    Per the mzML spec the SHA-1 covers every byte of the file up to and including the `<fileChecksum>` open tag.
+    */
+    pub fn verify_checksum(&self) -> Result<bool, MzMLParseError> {
+        const FILE_CHECKSUM: &str = "<fileChecksum>";
+        let index_list_offset = self.mzml_struct.index_list_offset;
+        let tail = read_element(
+            self.reader_at(index_list_offset),
+            FILE_CHECKSUM,
+            "fileChecksum",
+        )?;
+        let mut remaining = index_list_offset + tail.len() as u64;
+        let mut reader = self.reader_at(0);
+        let mut hasher = Sha1::new();
+        let mut buffer = [0; 8000];
+        while remaining > 0 {
+            let length = buffer.len().min(remaining as usize);
+            reader.read_exact(&mut buffer[..length])?;
+            hasher.update(&buffer[..length]);
+            remaining -= length as u64;
+        }
+        let checksum = writer::to_hex(&hasher.finalize());
+        Ok(checksum.eq_ignore_ascii_case(self.mzml_struct.file_checksum.trim()))
+    }
 }
 
 impl<'a> LazyMzML {
     /**Return an iterator of MassScan objects contained in the LazyMzML.
      */
-    pub fn iter_scan(&'a self) -> impl Iterator<Item = &ScanWithoutData> + 'a {
+    pub fn iter_scan(&'a self) -> impl Iterator<Item = &'a ScanWithoutData> + 'a {
         self.mzml_struct.mzml.run.spectrum_list.spectra.iter()
     }
 
     /**Return an iterator of MassScan objects the underlying data is additionally loaded from disk to create MassSpectrum.
+    Spectra whose data can't be retrieved are yielded as errors.
      */
-    pub fn iter_spectrum(&'a self) -> impl Iterator<Item = impl MassScan + MassSpectrum> + 'a {
+    pub fn iter_spectrum(
+        &'a self,
+    ) -> impl Iterator<Item = Result<ScanWithData, MzMLParseError>> + 'a {
         self.mzml_struct
             .mzml
             .run
             .spectrum_list
             .spectra
             .iter()
-            .map(|s| {
-                self.fetch_scan_data(s)
-                    .expect("Spectrum data should be retrievable")
+            .map(|s| self.fetch_scan_data(s))
+    }
+
+    /**As `iter_spectrum`, but only loading spectra whose index falls within [start, end).
+     */
+    pub fn iter_spectrum_range(
+        &'a self,
+        start: usize,
+        end: usize,
+    ) -> impl Iterator<Item = Result<ScanWithData, MzMLParseError>> + 'a {
+        self.iter_scan()
+            .filter(move |s| (start..end).contains(&s.index))
+            .map(|s| self.fetch_scan_data(s))
+    }
+
+    /**As `iter_spectrum`, but only loading spectra whose scan start time falls within [start, end).
+    Spectra without a scan start time are skipped.
+     */
+    pub fn iter_spectrum_in_rt_range(
+        &'a self,
+        start: Time,
+        end: Time,
+    ) -> impl Iterator<Item = Result<ScanWithData, MzMLParseError>> + 'a {
+        self.iter_scan()
+            .filter(move |s| s.rt().is_some_and(|rt| rt >= start && rt < end))
+            .map(|s| self.fetch_scan_data(s))
+    }
+
+    /**Return an iterator of the scans with the given ms level.
+     */
+    pub fn iter_scan_by_ms_level(
+        &'a self,
+        level: u16,
+    ) -> impl Iterator<Item = &'a ScanWithoutData> + 'a {
+        self.iter_scan()
+            .filter(move |s| s.ms_level() == Some(level))
+    }
+
+    /**As `iter_spectrum`, but only loading spectra with the given ms level.
+     */
+    pub fn iter_spectrum_by_ms_level(
+        &'a self,
+        level: u16,
+    ) -> impl Iterator<Item = Result<ScanWithData, MzMLParseError>> + 'a {
+        self.iter_scan_by_ms_level(level)
+            .map(|s| self.fetch_scan_data(s))
+    }
+
+    /**Return an iterator of the scans acquired with the given polarity.
+     */
+    pub fn iter_scan_by_polarity(
+        &'a self,
+        polarity: Polarity,
+    ) -> impl Iterator<Item = &'a ScanWithoutData> + 'a {
+        self.iter_scan()
+            .filter(move |s| s.polarity() == Some(polarity))
+    }
+
+    /**Return an iterator of the MS2 scans whose isolation window brackets `mz`.
+    In DIA data these are the scans of the window that fragmented precursors of that m/z.
+     */
+    pub fn scans_covering_mz(&'a self, mz: f64) -> impl Iterator<Item = &'a ScanWithoutData> + 'a {
+        self.iter_scan_by_ms_level(2).filter(move |s| {
+            s.precursor_list
+                .iter()
+                .flat_map(|l| l.precursors.iter())
+                .any(|precursor| precursor.isolation_window.contains(mz))
+        })
+    }
+
+    ///Return the number of scans with the given ms level.
+    pub fn count_by_ms_level(&self, level: u16) -> usize {
+        self.iter_scan_by_ms_level(level).count()
+    }
+
+    ///Load the data of a scan from disk.
+    pub fn fetch_scan_data(&self, scan: &ScanWithoutData) -> Result<ScanWithData, MzMLParseError> {
+        let offset = self
+            .scan_offsets
+            .get(&(scan.id))
+            .ok_or_else(|| MzMLParseError::MissingOffset(scan.id.clone()))?;
+        let reader = self.reader_at(*offset);
+        let xml_string = read_element(reader, "</spectrum>", &scan.id)?;
+        self.parse_scan_data(scan, &xml_string)
+    }
+
+    ///Parse the `<spectrum>` element read for `scan`, filling in what the file leaves to its context.
+    fn parse_scan_data(
+        &self,
+        scan: &ScanWithoutData,
+        xml: &str,
+    ) -> Result<ScanWithData, MzMLParseError> {
+        let mut spectrum: ScanWithData = from_str(xml)?;
+        if spectrum.index == MISSING_INDEX {
+            spectrum.index = scan.index;
+        }
+        spectrum.resolve_param_groups(&self.mzml_struct.mzml.referenceable_param_group_list);
+        Ok(spectrum)
+    }
+
+    /**Load the spectra with the given ids, returned in the order requested.
+    Spectra are read in file order, so pulling many identified spectra becomes a near sequential read.
+    Ids that aren't in the file are returned as `MissingOffset` errors.
+    */
+    pub fn fetch_many(&self, ids: &[String]) -> Vec<Result<ScanWithData, MzMLParseError>> {
+        let scans: HashMap<&String, &ScanWithoutData> =
+            self.iter_scan().map(|scan| (&scan.id, scan)).collect();
+        let mut order: Vec<usize> = (0..ids.len()).collect();
+        order.sort_by_key(|i| self.scan_offsets.get(&ids[*i]).copied().unwrap_or(u64::MAX));
+        let mut fetched: Vec<Option<Result<ScanWithData, MzMLParseError>>> =
+            (0..ids.len()).map(|_| None).collect();
+        for i in order {
+            fetched[i] = Some(match scans.get(&ids[i]) {
+                Some(scan) => self.fetch_scan_data(scan),
+                None => Err(MzMLParseError::MissingOffset(ids[i].clone())),
+            });
+        }
+        fetched.into_iter().flatten().collect()
+    }
+
+    /**Write the file back out as indexed mzML, with a freshly computed index and checksum.
+    Every element is copied verbatim, only the index and checksum are rewritten.
+     */
+    pub fn write_indexed<W: Write>(&self, writer: W) -> Result<(), MzMLParseError> {
+        self.write_indexed_from(writer, self.iter_scan().count(), |writer| {
+            for scan in self.iter_scan() {
+                let offset = self
+                    .scan_offsets
+                    .get(&scan.id)
+                    .ok_or_else(|| MzMLParseError::MissingOffset(scan.id.clone()))?;
+                let xml = read_element(self.reader_at(*offset), "</spectrum>", &scan.id)?;
+                writer.write_spectrum_xml(&scan.id, &xml)?;
+            }
+            Ok(())
+        })
+    }
+
+    /**Write indexed mzML holding `spectra` in place of the spectra of this file, e.g. after filtering or recalibrating them.
+    The header and chromatograms are copied from this file. Spectra are written, and re-indexed, in the order given.
+     */
+    pub fn write_indexed_spectra<W, I>(&self, writer: W, spectra: I) -> Result<(), MzMLParseError>
+    where
+        W: Write,
+        I: IntoIterator<Item = ScanWithData>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let spectra = spectra.into_iter();
+        self.write_indexed_from(writer, spectra.len(), |writer| {
+            for (index, mut spectrum) in spectra.enumerate() {
+                spectrum.index = index;
+                spectrum.update_counts();
+                writer.write_spectrum(&spectrum)?;
+            }
+            Ok(())
+        })
+    }
+
+    ///Write indexed mzML with this file's header and chromatograms, `write_spectra` writing the `count` spectra.
+    fn write_indexed_from<W, F>(
+        &self,
+        writer: W,
+        count: usize,
+        write_spectra: F,
+    ) -> Result<(), MzMLParseError>
+    where
+        W: Write,
+        F: FnOnce(&mut IndexedWriter<W>) -> Result<(), MzMLParseError>,
+    {
+        let run = &self.mzml_struct.mzml.run;
+        let mut writer = IndexedWriter::new(writer);
+        writer.write_raw(&self.header()?)?;
+        writer.write_raw(&list_open_tag(
+            "spectrumList",
+            count,
+            &run.spectrum_list.default_data_processing_ref,
+        ))?;
+        write_spectra(&mut writer)?;
+        writer.write_raw("</spectrumList>\n")?;
+        let chromatograms = &run.chromatogram_list.chromatograms;
+        writer.write_raw(&list_open_tag(
+            "chromatogramList",
+            chromatograms.len(),
+            &run.chromatogram_list.default_data_processing_ref,
+        ))?;
+        for chromatogram in chromatograms.iter() {
+            let offset = self
+                .chromatogram_offsets
+                .get(&chromatogram.id)
+                .ok_or_else(|| MzMLParseError::MissingOffset(chromatogram.id.clone()))?;
+            let reader = self.reader_at(*offset);
+            let xml = read_element(reader, "</chromatogram>", &chromatogram.id)?;
+            writer.write_chromatogram(&chromatogram.id, &xml)?;
+        }
+        writer.write_raw("</chromatogramList>\n</run>\n</mzML>\n")?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    ///Return a reader of the file starting at `position`, from the memory map when there is one.
+    fn reader_at(&self, position: u64) -> FileReader<'_> {
+        match &self.mmap {
+            Some(mmap) => {
+                let start = usize::try_from(position).map_or(mmap.len(), |p| p.min(mmap.len()));
+                FileReader::Mapped(&mmap[start..])
+            }
+            None => FileReader::File(PositionedReader::new(&self.file, position)),
+        }
+    }
+
+    /**Write every MSn spectrum as an MGF peak list, for handing to search engines.
+    MS1 spectra, and spectra without an ms level, are skipped.
+     */
+    pub fn to_mgf<W: Write>(&self, mut writer: W) -> Result<(), MzMLParseError> {
+        for scan in self
+            .iter_scan()
+            .filter(|s| s.ms_level().is_some_and(|level| level > 1))
+        {
+            self.fetch_scan_data(scan)?.write_mgf(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /**Return the text of the file before the spectrum list.
+    This holds the indexedmzML, mzML and run open tags and every mzML header element, copied verbatim.
+    */
+    fn header(&self) -> Result<String, MzMLParseError> {
+        const SPECTRUM_LIST: &str = "<spectrumList";
+        let mut header = read_element(self.reader_at(0), SPECTRUM_LIST, "spectrumList")?;
+        header.truncate(header.len() - SPECTRUM_LIST.len());
+        Ok(header)
+    }
+
+    /**Infer how the file was acquired.
+    SRM chromatograms mark a MRM run. Otherwise the MS2 isolation windows are compared:
+    a small set of windows repeated each cycle is DIA, windows that mostly differ scan to scan
+    (following whichever precursor was picked) is DDA.
+    */
+    pub fn acquisition_type(&self) -> AcquisitionType {
+        self.mzml_struct.mzml.run.acquisition_type()
+    }
+
+    /**Return the retention time intervals between consecutive MS1 scans that are longer than `max_gap`.
+    These mark acquisition dropouts, such as instrument stalls or missing scans.
+    */
+    pub fn rt_gaps(&self, max_gap: Time) -> Vec<(Time, Time)> {
+        let mut rts: Vec<Time> = self
+            .iter_scan()
+            .filter(|s| s.ms_level() == Some(1))
+            .filter_map(|s| s.rt())
+            .collect();
+        rts.sort_by(|a, b| a.value.total_cmp(&b.value));
+        rts.windows(2)
+            .filter(|pair| pair[1] - pair[0] > max_gap)
+            .map(|pair| (pair[0], pair[1]))
+            .collect()
+    }
+
+    /**Return the scan whose retention time is closest to `target`, optionally only considering scans of one ms level.
+    Uses a retention time index built when the file is opened, so a lookup is a binary search. With `ms_level`,
+    the search then walks outwards from that point to the nearest scans of the level, linear in the scans skipped.
+    Returns None when no scan (of that level) has a retention time.
+    */
+    pub fn nearest_scan_by_rt(
+        &self,
+        target: Time,
+        ms_level: Option<u16>,
+    ) -> Option<&ScanWithoutData> {
+        let spectra = &self.mzml_struct.mzml.run.spectrum_list.spectra;
+        let wanted = |(_, position): &&(Time, usize)| {
+            ms_level.map_or(true, |level| {
+                spectra
+                    .get(*position)
+                    .is_some_and(|s| s.ms_level() == Some(level))
             })
+        };
+        let split = self.rt_index.partition_point(|(rt, _)| *rt < target);
+        let before = self.rt_index[..split].iter().rev().find(wanted);
+        let after = self.rt_index[split..].iter().find(wanted);
+        let (_, position) = match (before, after) {
+            (Some(before), Some(after)) => {
+                if target - before.0 <= after.0 - target {
+                    before
+                } else {
+                    after
+                }
+            }
+            (before, after) => before.or(after)?,
+        };
+        spectra.get(*position)
     }
 
-    pub fn fetch_scan_data(&self, scan: &ScanWithoutData) -> Option<ScanWithData> {
-        const BUFFER_SIZE: usize = 8000;
-        let offset = self.scan_offsets.get(&(scan.id))?;
-        let file = &self.file;
-        let mut xml_string = String::from("");
-        let mut buffer = [0; BUFFER_SIZE];
-        let mut reader = BufReader::new(file);
-        reader.seek(SeekFrom::Start(*offset as u64)).unwrap();
-        let mut number_of_buffers: usize = 0;
-        loop {
-            let number_bytes = reader.read(&mut buffer[..]).ok()?;
-            xml_string.push_str(std::str::from_utf8(&buffer[..number_bytes]).ok()?);
-            if let Some(n) = xml_string[xml_string
-                .len()
-                .checked_sub(BUFFER_SIZE)
-                .unwrap_or_default()..]
-                .find(r"</spectrum>")
-            {
-                xml_string.truncate(number_of_buffers * BUFFER_SIZE + n + 11);
-                break;
+    /**Build a fragment extracted ion chromatogram for DIA: (retention time, summed intensity) of the peaks
+    within `tol` of `target_mz` in every MS2 scan, across all isolation windows, sorted by retention time.
+    Scans without a retention time are skipped; scans with no matching peak contribute 0.0.
+    */
+    pub fn demultiplex(
+        &self,
+        target_mz: f64,
+        tol: Tolerance,
+    ) -> Result<Vec<(Time, f64)>, MzMLParseError> {
+        let mut chromatogram = Vec::new();
+        for scan in self.iter_scan_by_ms_level(2) {
+            let rt = match scan.rt() {
+                Some(rt) => rt,
+                None => continue,
+            };
+            let spectrum = self.fetch_scan_data(scan)?;
+            chromatogram.push((rt, spectrum.summed_intensity(target_mz, tol)?));
+        }
+        chromatogram.sort_by(|a, b| a.0.value.total_cmp(&b.0.value));
+        Ok(chromatogram)
+    }
+
+    ///Return the number of spectra the spectrumList declares, without iterating them.
+    pub fn spectrum_count(&self) -> usize {
+        self.mzml_struct.mzml.run.spectrum_list.count
+    }
+
+    ///Return the number of chromatograms the chromatogramList declares.
+    pub fn chromatogram_count(&self) -> usize {
+        self.mzml_struct.mzml.run.chromatogram_list.count
+    }
+
+    /**Check the declared spectrum and chromatogram counts against the elements actually parsed.
+    A mismatch usually means the file was truncated or edited by hand.
+    */
+    pub fn check_counts(&self) -> Result<(), MzMLParseError> {
+        let run = &self.mzml_struct.mzml.run;
+        let lists = [
+            (
+                "spectrumList",
+                self.spectrum_count(),
+                run.spectrum_list.spectra.len(),
+            ),
+            (
+                "chromatogramList",
+                self.chromatogram_count(),
+                run.chromatogram_list.chromatograms.len(),
+            ),
+        ];
+        for (list, declared, found) in lists {
+            if declared != found {
+                return Err(MzMLParseError::CountMismatch(
+                    list.to_string(),
+                    declared,
+                    found,
+                ));
             }
-            number_of_buffers += 1;
         }
-        let spectrum: ScanWithData = from_str(&xml_string).unwrap();
-        Some(spectrum)
+        Ok(())
+    }
+
+    ///Return the fileDescription of the mzML header.
+    pub fn file_description(&self) -> &FileDescription {
+        &self.mzml_struct.mzml.file_description
+    }
+
+    ///Return the files this mzML was converted from, with their names, locations and checksums.
+    pub fn source_files(&self) -> &[SourceFile] {
+        self.file_description()
+            .source_file_list
+            .as_ref()
+            .map(|list| &list.source_files[..])
+            .unwrap_or_default()
+    }
+
+    ///Return every instrument configuration of the file.
+    pub fn instrument_configurations(&self) -> &[InstrumentConfiguration] {
+        &self
+            .mzml_struct
+            .mzml
+            .instrument_configuration_list
+            .configurations
+    }
+
+    /**Return the instrument model of the run's default instrument configuration,
+    or of the first configuration if the run names none.
+    */
+    pub fn instrument_model(&self) -> Option<&ControlledVocabularyParameter> {
+        let configurations = self.instrument_configurations();
+        let configuration = match &self
+            .mzml_struct
+            .mzml
+            .run
+            .default_instrument_configuration_ref
+        {
+            Some(id) => configurations.iter().find(|c| &c.id == id),
+            None => configurations.first(),
+        }?;
+        configuration.instrument_model()
+    }
+
+    /**Return the spectrum mode declared for the whole file in its fileDescription.
+    Returns None when the file declares both modes or neither, in which case check `spectrum_type()` of each scan.
+    */
+    pub fn file_spectrum_mode(&self) -> Option<SpectrumMode> {
+        SpectrumMode::from_cvs(&self.mzml_struct.mzml.file_description.file_content.cv_param)
+    }
+}
+
+///How the spectra in a file were acquired, see `LazyMzML::acquisition_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquisitionType {
+    ///Data dependent acquisition, precursors picked from the preceding MS1 scan.
+    Dda,
+    ///Data independent acquisition, fixed isolation windows repeated every cycle.
+    Dia,
+    ///Multiple reaction monitoring, SRM transitions stored as chromatograms.
+    Mrm,
+    ///Only MS1 spectra.
+    MsOnly,
+    Unknown,
+}
+
+fn list_open_tag(name: &str, count: usize, default_data_processing_ref: &Option<String>) -> String {
+    match default_data_processing_ref {
+        Some(reference) => format!(
+            "<{} count=\"{}\" defaultDataProcessingRef=\"{}\">\n",
+            name,
+            count,
+            quick_xml::escape::escape(reference)
+        ),
+        None => format!("<{} count=\"{}\">\n", name, count),
+    }
+}
+
+///Reads the file of a LazyMzML from a fixed offset, see `LazyMzML::reader_at`.
+enum FileReader<'a> {
+    File(PositionedReader<'a>),
+    Mapped(&'a [u8]),
+}
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            FileReader::File(reader) => reader.read(buf),
+            FileReader::Mapped(bytes) => bytes.read(buf),
+        }
+    }
+}
+
+/**A file read at any offset, by any number of threads at once.
+Unix and windows use positioned reads, which never touch the file cursor. Other targets seek
+and read under a lock, so concurrent readers never move the cursor under each other.
+*/
+#[derive(Debug)]
+struct SharedFile {
+    #[cfg(any(unix, windows))]
+    file: File,
+    #[cfg(not(any(unix, windows)))]
+    file: std::sync::Mutex<File>,
+}
+impl SharedFile {
+    fn new(file: File) -> Self {
+        SharedFile {
+            #[cfg(any(unix, windows))]
+            file,
+            #[cfg(not(any(unix, windows)))]
+            file: std::sync::Mutex::new(file),
+        }
+    }
+    fn into_inner(self) -> File {
+        #[cfg(any(unix, windows))]
+        let file = self.file;
+        #[cfg(not(any(unix, windows)))]
+        let file = self
+            .file
+            .into_inner()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        file
+    }
+    fn read_at(&self, buf: &mut [u8], position: u64) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(&self.file, buf, position)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(&self.file, buf, position)?;
+        #[cfg(not(any(unix, windows)))]
+        let n = {
+            //A panic mid read leaves only the cursor behind, which every read sets first anyway.
+            let mut file = self
+                .file
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            file.seek(SeekFrom::Start(position))?;
+            file.read(buf)?
+        };
+        Ok(n)
+    }
+}
+
+///Reads a `SharedFile` from a fixed offset onwards.
+struct PositionedReader<'a> {
+    file: &'a SharedFile,
+    position: u64,
+}
+impl<'a> PositionedReader<'a> {
+    fn new(file: &'a SharedFile, position: u64) -> Self {
+        PositionedReader { file, position }
+    }
+}
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read_at(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/**Read from `reader` up to and including the first `end_tag`, e.g. `</spectrum>`.
+Reads may return any number of bytes, `end_tag` is found even when split across two reads.
+*/
+fn read_element<R: Read>(mut reader: R, end_tag: &str, id: &str) -> Result<String, MzMLParseError> {
+    const BUFFER_SIZE: usize = 8000;
+    let end_tag = end_tag.as_bytes();
+    let mut xml = Vec::new();
+    let mut buffer = [0; BUFFER_SIZE];
+    loop {
+        let number_bytes = match reader.read(&mut buffer[..]) {
+            Ok(0) => return Err(MzMLParseError::UnexpectedEof(id.to_string())),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if append_until(&mut xml, &buffer[..number_bytes], end_tag) {
+            return String::from_utf8(xml).map_err(|e| e.utf8_error().into());
+        }
+    }
+}
+
+/**Append `chunk` to `xml`, returning true once `xml` holds `end_tag`, truncated just after it.
+Only the newly appended bytes, and the few before them an end tag could straddle, are searched.
+*/
+fn append_until(xml: &mut Vec<u8>, chunk: &[u8], end_tag: &[u8]) -> bool {
+    let search_start = xml.len().saturating_sub(end_tag.len() - 1);
+    xml.extend_from_slice(chunk);
+    match xml[search_start..]
+        .windows(end_tag.len())
+        .position(|window| window == end_tag)
+    {
+        Some(n) => {
+            xml.truncate(search_start + n + end_tag.len());
+            true
+        }
+        None => false,
     }
 }
 
@@ -143,6 +794,28 @@ pub enum MzMLParseError {
     ZlibDecodeError(#[from] zune_inflate::errors::InflateDecodeErrors),
     #[error("Base64 parsing error, scan data is not parsable: {0}")]
     Base64DecodeError(#[from] base64::DecodeError),
+    #[error("MS-Numpress decoding error: {0}")]
+    NumpressDecodeError(&'static str),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Spectrum data is not valid UTF-8: {0}")]
+    Utf8Error(#[from] std::str::Utf8Error),
+    #[error("No index offset for spectrum {0}")]
+    MissingOffset(String),
+    #[error("Reached the end of the file while reading {0}")]
+    UnexpectedEof(String),
+    #[error("File does not match its checksum {0}")]
+    ChecksumMismatch(String),
+    #[error("Unparsable value for cv parameter {0}: {1}")]
+    CvValueParseError(String, String),
+    #[error("{0} declares {1} elements but holds {2}")]
+    CountMismatch(String, usize, usize),
+    #[error("Spectrum {0} has no {1}")]
+    MissingArray(String, &'static str),
+    #[error("Spectrum {0} decodes to {1} m/z and {2} intensity values, expected {3}")]
+    ArrayLengthMismatch(String, usize, usize, usize),
+    #[error("Unsupported binary data size: {0} bit")]
+    UnknownDataSize(u8),
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -153,7 +826,7 @@ struct IndexedMzML {
     mzml: MzML<ScanWithoutData>,
     index_list: Option<IndexList>,
     index: Option<Index>,
-    index_list_offset: usize,
+    index_list_offset: u64,
     file_checksum: String,
 }
 
@@ -161,10 +834,101 @@ struct IndexedMzML {
 #[serde(rename_all = "camelCase")]
 #[serde(rename = "mzML")]
 struct MzML<T: MassScan> {
+    #[serde(default)]
+    file_description: FileDescription,
+    #[serde(default)]
+    referenceable_param_group_list: ReferenceableParamGroupList,
     software_list: SoftwareList,
+    #[serde(default)]
+    instrument_configuration_list: InstrumentConfigurationList,
     run: MzMLRun<T>,
 }
 
+///The `<fileDescription>` of the mzML header, describing the file as a whole.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileDescription {
+    #[serde(default)]
+    pub file_content: FileContent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_file_list: Option<SourceFileList>,
+}
+///The kinds of spectra contained in the file.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileContent {
+    #[serde(default)]
+    pub cv_param: Vec<ControlledVocabularyParameter>,
+}
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceFileList {
+    #[serde(rename = "@count", default)]
+    pub count: usize,
+    #[serde(rename = "sourceFile", default)]
+    pub source_files: Vec<SourceFile>,
+}
+///A file the mzML was converted from, typically the raw vendor file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceFile {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(rename = "@location")]
+    pub location: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
+    pub cv_param: Vec<ControlledVocabularyParameter>,
+}
+impl SourceFile {
+    ///Return the SHA-1 (MS:1000569), or failing that MD5 (MS:1000568), checksum recorded for the file.
+    pub fn checksum(&self) -> Option<&str> {
+        ["MS:1000569", "MS:1000568"].iter().find_map(|accession| {
+            self.cv_param
+                .iter()
+                .find(|cv| cv.accession == *accession)
+                .map(|cv| &cv.value[..])
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct InstrumentConfigurationList {
+    #[serde(rename = "@count", default)]
+    count: usize,
+    #[serde(rename = "instrumentConfiguration", default)]
+    configurations: Vec<InstrumentConfiguration>,
+}
+///An instrument setup used in the run. Its components (source, analyzer, detector) are not parsed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstrumentConfiguration {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
+    pub cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_param: Vec<UserParameter>,
+}
+impl InstrumentConfiguration {
+    /**Return the instrument model cv parameter, e.g. "LTQ FT".
+    mzML requires exactly one instrument model term, so this is the first cv parameter that isn't a
+    known instrument attribute such as the serial number.
+    */
+    pub fn instrument_model(&self) -> Option<&ControlledVocabularyParameter> {
+        const INSTRUMENT_ATTRIBUTES: [&str; 3] = ["MS:1000529", "MS:1000032", "MS:1000496"];
+        self.cv_param
+            .iter()
+            .find(|cv| !INSTRUMENT_ATTRIBUTES.contains(&&cv.accession[..]))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 struct IndexList {
@@ -179,7 +943,7 @@ struct IndexList {
 struct Index {
     #[serde(rename = "@name")]
     name: String,
-    #[serde(rename = "$value")]
+    #[serde(rename = "$value", default)]
     offsets: Vec<Offset>,
 }
 
@@ -189,7 +953,7 @@ struct Offset {
     #[serde(rename = "@idRef")]
     id_ref: String,
     #[serde(rename = "$value")]
-    offset: usize,
+    offset: u64,
 }
 impl Hash for Offset {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -223,6 +987,8 @@ struct Software {
 #[serde(rename_all = "camelCase")]
 #[serde(rename = "run")]
 struct MzMLRun<T: MassScan> {
+    #[serde(rename = "@defaultInstrumentConfigurationRef")]
+    default_instrument_configuration_ref: Option<String>,
     spectrum_list: SpectrumList<T>,
     chromatogram_list: ChromatogramList,
 }
@@ -232,16 +998,104 @@ struct MzMLRun<T: MassScan> {
 struct SpectrumList<T: MassScan> {
     #[serde(rename = "@count")]
     count: usize,
-    #[serde(rename = "$value")]
+    #[serde(rename = "@defaultDataProcessingRef")]
+    default_data_processing_ref: Option<String>,
+    #[serde(rename = "$value", default = "Vec::new")]
     spectra: Vec<T>,
 }
+impl MzMLRun<ScanWithoutData> {
+    ///See `LazyMzML::acquisition_type`.
+    fn acquisition_type(&self) -> AcquisitionType {
+        if self.chromatogram_list.chromatograms.iter().any(|c| {
+            c.cv_param
+                .iter()
+                .any(|cv| cv.name.find("selected reaction monitoring").is_some())
+        }) {
+            return AcquisitionType::Mrm;
+        }
+        let ms_levels: Vec<u16> = self
+            .spectrum_list
+            .spectra
+            .iter()
+            .filter_map(|s| s.ms_level())
+            .collect();
+        if ms_levels.is_empty() {
+            return AcquisitionType::Unknown;
+        }
+        if ms_levels.iter().all(|level| *level == 1) {
+            return AcquisitionType::MsOnly;
+        }
+        //Windows are keyed at 0.01 m/z resolution so that float noise doesn't split them.
+        let windows: Vec<(i64, i64, i64)> = self
+            .spectrum_list
+            .spectra
+            .iter()
+            .filter(|s| s.ms_level().unwrap_or_default() > 1)
+            .filter_map(|s| s.precursor_list.as_ref()?.precursors.first())
+            .filter_map(|p| {
+                let window = &p.isolation_window;
+                let key = |value: f64| (value * 100.0).round() as i64;
+                Some((
+                    key(window.target_mz()?),
+                    key(window.lower_offset().unwrap_or_default()),
+                    key(window.upper_offset().unwrap_or_default()),
+                ))
+            })
+            .collect();
+        if windows.is_empty() {
+            return AcquisitionType::Unknown;
+        }
+        let distinct: std::collections::HashSet<_> = windows.iter().collect();
+        if distinct.len() * 2 <= windows.len() {
+            AcquisitionType::Dia
+        } else {
+            AcquisitionType::Dda
+        }
+    }
+}
+impl SpectrumList<ScanWithoutData> {
+    ///Give spectra that were written without an index attribute their position in the list.
+    fn assign_missing_indices(&mut self) {
+        for (position, spectrum) in self.spectra.iter_mut().enumerate() {
+            if spectrum.index == MISSING_INDEX {
+                spectrum.index = position;
+            }
+        }
+    }
+}
+
+///Expand the param group references shared by `ScanWithData` and `ScanWithoutData`.
+fn resolve_spectrum_param_groups(
+    groups: &ReferenceableParamGroupList,
+    refs: &mut Vec<ReferenceableParamGroupRef>,
+    cv_param: &mut Vec<ControlledVocabularyParameter>,
+    scan_list: &mut ScanList,
+    precursor_list: Option<&mut PrecursorList>,
+) {
+    groups.expand(refs, cv_param);
+    scan_list.resolve_param_groups(groups);
+    for precursor in precursor_list
+        .into_iter()
+        .flat_map(|list| list.precursors.iter_mut())
+    {
+        precursor.resolve_param_groups(groups);
+    }
+}
+
+///Placeholder for a spectrum index attribute that is absent, replaced once the position is known.
+const MISSING_INDEX: usize = usize::MAX;
+fn missing_index() -> usize {
+    MISSING_INDEX
+}
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 struct ChromatogramList {
     #[serde(rename = "@count")]
-    count: u16,
-    #[serde(rename = "$value")]
+    count: usize,
+    #[serde(rename = "@defaultDataProcessingRef")]
+    default_data_processing_ref: Option<String>,
+    #[serde(rename = "$value", default)]
     chromatograms: Vec<Chromatogram>,
 }
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -250,143 +1104,622 @@ struct Chromatogram {
     #[serde(rename = "@id")]
     id: String,
     #[serde(rename = "@index")]
-    index: u16,
+    index: usize,
+    #[serde(default)]
+    cv_param: Vec<ControlledVocabularyParameter>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename = "spectrum")]
 #[serde(rename_all = "camelCase")]
 pub struct ScanWithData {
-    #[serde(rename = "@index")]
+    #[serde(rename = "@index", default = "missing_index")]
     index: usize,
     #[serde(rename = "@id")]
     id: String,
     #[serde(rename = "@defaultArrayLength")]
     default_array_length: usize,
-    cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(rename = "@dataProcessingRef", skip_serializing_if = "Option::is_none")]
+    data_processing_ref: Option<String>,
+    #[serde(rename = "@sourceFileRef", skip_serializing_if = "Option::is_none")]
+    source_file_ref: Option<String>,
+    #[serde(rename = "@spotID", skip_serializing_if = "Option::is_none")]
+    spot_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
     #[serde(default)]
-    pub precursor_list: Option<PrecursorList>,
+    cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_param: Vec<UserParameter>,
     scan_list: ScanList,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precursor_list: Option<PrecursorList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    product_list: Option<ProductList>,
     binary_data_array_list: BinaryDataArrayList,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename = "spectrum")]
 #[serde(rename_all = "camelCase")]
 pub struct ScanWithoutData {
-    #[serde(rename = "@index")]
+    #[serde(rename = "@index", default = "missing_index")]
     index: usize,
     #[serde(rename = "@id")]
     id: String,
     #[serde(rename = "@defaultArrayLength")]
     default_array_length: usize,
-    cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
     #[serde(default)]
-    pub precursor_list: Option<PrecursorList>,
+    cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_param: Vec<UserParameter>,
     scan_list: ScanList,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precursor_list: Option<PrecursorList>,
 }
 
 impl MassSpectrum for ScanWithData {
     fn peaks(&self) -> Result<Vec<(f64, f64)>, MzMLParseError> {
-        let mz_array = self
-            .binary_data_array_list
-            .find_binary_by_cv_name("m/z array")
-            .expect("All spectra should have an m/z array");
-        let intensity_array = self
-            .binary_data_array_list
-            .find_binary_by_cv_name("intensity array")
-            .expect("All spectra should have an intensity array");
+        let (mz_array, intensity_array) = match self.peak_arrays()? {
+            Some(arrays) => arrays,
+            None => return Ok(Vec::new()),
+        };
         let mz = mz_array.decode()?;
         let intensity = intensity_array.decode()?;
-        Ok(mz.into_iter().zip(intensity.into_iter()).collect())
+        self.check_lengths((mz_array, mz.len()), (intensity_array, intensity.len()))?;
+        Ok(mz.into_iter().zip(intensity).collect())
+    }
+    fn peaks_f32(&self) -> Result<Vec<(f32, f32)>, MzMLParseError> {
+        let (mz_array, intensity_array) = match self.peak_arrays()? {
+            Some(arrays) => arrays,
+            None => return Ok(Vec::new()),
+        };
+        let mz = mz_array.decode_typed()?.into_f32();
+        let intensity = intensity_array.decode_typed()?.into_f32();
+        self.check_lengths((mz_array, mz.len()), (intensity_array, intensity.len()))?;
+        Ok(mz.into_iter().zip(intensity).collect())
     }
 }
-impl MassScan for ScanWithoutData {
-    fn rt(&self) -> Option<uom::si::f32::Time> {
-        let rt_cv = self
+impl ScanWithData {
+    /**Return the m/z and intensity arrays, or None for a spectrum with no peaks.
+    A spectrum declaring no peaks may leave out either array, as long as any array it keeps is empty.
+    Arrays that are present are returned even when no peaks are declared, so `check_lengths` catches a wrong declaration.
+    */
+    fn peak_arrays(&self) -> Result<Option<(&BinaryDataArray, &BinaryDataArray)>, MzMLParseError> {
+        let list = &self.binary_data_array_list;
+        let (mz, intensity) = match (
+            list.find_binary_by_cv_name("m/z array"),
+            list.find_binary_by_cv_name("intensity array"),
+        ) {
+            (Some(mz), Some(intensity)) => return Ok(Some((mz, intensity))),
+            arrays => arrays,
+        };
+        if self.default_array_length == 0 {
+            match mz.or(intensity) {
+                Some(array) if !array.decode_typed()?.is_empty() => {}
+                _ => return Ok(None),
+            }
+        }
+        let missing = if mz.is_none() {
+            "m/z array"
+        } else {
+            "intensity array"
+        };
+        Err(MzMLParseError::MissingArray(self.id.clone(), missing))
+    }
+    /**Error unless the m/z and intensity arrays, each paired with its decoded length, hold the same number of values
+    and each holds its declared length, so peaks are never silently dropped.
+    An array's own arrayLength overrides the spectrum's defaultArrayLength.
+    */
+    fn check_lengths(
+        &self,
+        (mz_array, mz): (&BinaryDataArray, usize),
+        (intensity_array, intensity): (&BinaryDataArray, usize),
+    ) -> Result<(), MzMLParseError> {
+        let expected_mz = mz_array.array_length.unwrap_or(self.default_array_length);
+        let expected_intensity = intensity_array
+            .array_length
+            .unwrap_or(self.default_array_length);
+        if mz != intensity || mz != expected_mz || intensity != expected_intensity {
+            let expected = if mz != expected_mz {
+                expected_mz
+            } else {
+                expected_intensity
+            };
+            return Err(MzMLParseError::ArrayLengthMismatch(
+                self.id.clone(),
+                mz,
+                intensity,
+                expected,
+            ));
+        }
+        Ok(())
+    }
+    ///Set the count attribute of every list in the spectrum to the number of elements it holds.
+    fn update_counts(&mut self) {
+        self.scan_list.count = self.scan_list.scan.len();
+        for scan_window_list in self
             .scan_list
             .scan
-            .first()?
-            .cv_param
-            .iter()
-            .find(|c| c.name.find("scan start time").is_some())?;
-        let time: f32 = rt_cv.value.parse().unwrap();
-        let unit_string = rt_cv.unit_name.as_ref()?;
-        match &unit_string[..] {
-            "minute" => Some(Time::new::<minute>(time)),
-            "second" => Some(Time::new::<second>(time)),
-            _ => Some(Time::new::<minute>(time)),
+            .iter_mut()
+            .filter_map(|scan| scan.scan_window_list.as_mut())
+        {
+            scan_window_list.count = scan_window_list.scan_windows.len();
         }
-    }
-    fn ms_level(&self) -> Option<u16> {
-        self.cv_param
-            .iter()
-            .find(|c| c.name.find("ms level").is_some())?
-            .value
-            .parse()
-            .ok()
+        if let Some(product_list) = self.product_list.as_mut() {
+            product_list.count = product_list.products.len();
+        }
+        if let Some(precursor_list) = self.precursor_list.as_mut() {
+            precursor_list.count = precursor_list.precursors.len();
+            for precursor in precursor_list.precursors.iter_mut() {
+                if let Some(selected_ion_list) = precursor.selected_ion_list.as_mut() {
+                    selected_ion_list.count = selected_ion_list.selected_ions.len();
+                }
+            }
+        }
+        self.binary_data_array_list.count = self.binary_data_array_list.arrays.len() as u16;
+    }
+    fn resolve_param_groups(&mut self, groups: &ReferenceableParamGroupList) {
+        resolve_spectrum_param_groups(
+            groups,
+            &mut self.referenceable_param_group_ref,
+            &mut self.cv_param,
+            &mut self.scan_list,
+            self.precursor_list.as_mut(),
+        );
+        for product in self
+            .product_list
+            .iter_mut()
+            .flat_map(|list| list.products.iter_mut())
+        {
+            if let Some(window) = product.isolation_window.as_mut() {
+                groups.expand(
+                    &mut window.referenceable_param_group_ref,
+                    &mut window.cv_param,
+                );
+            }
+        }
+        for array in self.binary_data_array_list.arrays.iter_mut() {
+            groups.expand(
+                &mut array.referenceable_param_group_ref,
+                &mut array.cv_param,
+            );
+        }
+    }
+    /**Build a centroided spectrum from (m/z, intensity) pairs, e.g. simulated peaks, for writing with `MzMLBuilder`.
+    The arrays are stored uncompressed as 64-bit little endian floats. The index is assigned when the spectrum is written.
+    */
+    pub fn new(id: String, peaks: &[(f64, f64)], ms_level: u16, rt: Time) -> ScanWithData {
+        let spectrum_type = match ms_level {
+            1 => ms_cv("MS:1000579", "MS1 spectrum", ""),
+            _ => ms_cv("MS:1000580", "MSn spectrum", ""),
+        };
+        let mz_array = ControlledVocabularyParameter {
+            unit_cv_ref: Some(String::from("MS")),
+            unit_accession: Some(String::from("MS:1000040")),
+            unit_name: Some(String::from("m/z")),
+            ..ms_cv("MS:1000514", "m/z array", "")
+        };
+        let intensity_array = ControlledVocabularyParameter {
+            unit_cv_ref: Some(String::from("MS")),
+            unit_accession: Some(String::from("MS:1000131")),
+            unit_name: Some(String::from("number of detector counts")),
+            ..ms_cv("MS:1000515", "intensity array", "")
+        };
+        let scan = ScanWithData {
+            index: 0,
+            id,
+            default_array_length: peaks.len(),
+            data_processing_ref: None,
+            source_file_ref: None,
+            spot_id: None,
+            referenceable_param_group_ref: Vec::new(),
+            cv_param: vec![
+                ms_cv("MS:1000511", "ms level", &ms_level.to_string()),
+                spectrum_type,
+                ms_cv("MS:1000127", "centroid spectrum", ""),
+            ],
+            user_param: Vec::new(),
+            scan_list: ScanList {
+                count: 1,
+                cv_param: vec![ms_cv("MS:1000795", "no combination", "")],
+                scan: vec![Scan::default()],
+                ..ScanList::default()
+            },
+            precursor_list: None,
+            product_list: None,
+            binary_data_array_list: BinaryDataArrayList {
+                count: 2,
+                arrays: vec![
+                    BinaryDataArray::encode_f64(peaks.iter().map(|(mz, _)| *mz), mz_array),
+                    BinaryDataArray::encode_f64(
+                        peaks.iter().map(|(_, intensity)| *intensity),
+                        intensity_array,
+                    ),
+                ],
+            },
+        };
+        scan.with_rt(rt)
+    }
+    /**Return a copy of the scan with a precursor added, selected at `mz` with an optional charge state.
+    The isolation window is centered on `mz`; the activation is left for the caller to describe.
+    */
+    pub fn with_precursor(&self, mz: f64, charge: Option<i32>) -> ScanWithData {
+        let mz_cv = |accession: &str, name: &str| ControlledVocabularyParameter {
+            unit_cv_ref: Some(String::from("MS")),
+            unit_accession: Some(String::from("MS:1000040")),
+            unit_name: Some(String::from("m/z")),
+            ..ms_cv(accession, name, &mz.to_string())
+        };
+        let mut selected_ion = vec![mz_cv("MS:1000744", "selected ion m/z")];
+        if let Some(charge) = charge {
+            selected_ion.push(ms_cv("MS:1000041", "charge state", &charge.to_string()));
+        }
+        let precursor = Precursor {
+            reference_spectrum: None,
+            source_file_ref: None,
+            external_spectrum_id: None,
+            isolation_window: IsolationWindow {
+                cv_param: vec![mz_cv("MS:1000827", "isolation window target m/z")],
+                ..IsolationWindow::default()
+            },
+            selected_ion_list: Some(SelectedIonList {
+                count: 1,
+                selected_ions: vec![SelectedIon {
+                    referenceable_param_group_ref: Vec::new(),
+                    cv_param: selected_ion,
+                    user_param: Vec::new(),
+                }],
+            }),
+            activation: Activation::default(),
+        };
+        let mut scan = self.clone();
+        scan.precursor_list
+            .get_or_insert_with(|| PrecursorList {
+                count: 0,
+                precursors: Vec::new(),
+            })
+            .precursors
+            .push(precursor);
+        scan.update_counts();
+        scan
+    }
+    /**Return a copy of the scan with its scan start time set to `new_rt`.
+    The existing scan start time cv parameter keeps its unit, a missing one is added in minutes.
+    */
+    pub fn with_rt(&self, new_rt: Time) -> ScanWithData {
+        let mut scan = self.clone();
+        if scan.scan_list.scan.is_empty() {
+            scan.scan_list.scan.push(Scan::default());
+        }
+        let cv_params = &mut scan.scan_list.scan[0].cv_param;
+        match cv_params
+            .iter_mut()
+            .find(|c| c.name.find("scan start time").is_some())
+        {
+            Some(rt_cv) => {
+                rt_cv.value = match rt_cv.unit_name.as_deref() {
+                    Some("second") => new_rt.get::<second>(),
+                    _ => new_rt.get::<minute>(),
+                }
+                .to_string();
+            }
+            None => cv_params.push(ControlledVocabularyParameter {
+                cv_ref: String::from("MS"),
+                accession: String::from("MS:1000016"),
+                name: String::from("scan start time"),
+                value: new_rt.get::<minute>().to_string(),
+                unit_cv_ref: Some(String::from("UO")),
+                unit_accession: Some(String::from("UO:0000031")),
+                unit_name: Some(String::from("minute")),
+            }),
+        }
+        scan
+    }
+    ///Return a copy of the scan with its id set to `id`.
+    pub fn with_id(&self, id: String) -> ScanWithData {
+        let mut scan = self.clone();
+        scan.id = id;
+        scan
+    }
+    /**Return the total ion current of the spectrum.
+    The recorded `total_ion_current()` is used when present, otherwise the intensities are decoded and summed.
+    */
+    pub fn computed_tic(&self) -> Result<f64, MzMLParseError> {
+        if let Some(tic) = self.total_ion_current() {
+            return Ok(tic);
+        }
+        Ok(self
+            .peaks()?
+            .into_iter()
+            .map(|(_, intensity)| intensity)
+            .sum())
+    }
+    /**Decode every binary data array of the spectrum, in file order, labeled by the name of its array type.
+    This reaches arrays `peaks()` ignores, such as wavelength (MS:1000617) or ion mobility arrays.
+    */
+    pub fn binary_arrays(&self) -> Result<Vec<(&str, Vec<f64>)>, MzMLParseError> {
+        Ok(self
+            .decoded_arrays()?
+            .into_iter()
+            .map(|(array_type, data)| (array_type, data.into_f64()))
+            .collect())
+    }
+    /**Decode every binary data array of the spectrum like `binary_arrays`, each in the type it was stored as.
+    This tells which precision the source used, e.g. a 32-bit intensity array next to a 64-bit m/z array.
+    */
+    pub fn decoded_arrays(&self) -> Result<Vec<(&str, DecodedArray)>, MzMLParseError> {
+        self.binary_data_array_list
+            .arrays
+            .iter()
+            .map(|array| Ok((array.array_type(), array.decode_typed()?)))
+            .collect()
+    }
+    /**Estimate the noise level of the spectrum as the median of its non-zero intensities.
+    Returns 0.0 for a spectrum without any signal.
+    */
+    pub fn estimate_noise(&self) -> Result<f64, MzMLParseError> {
+        let mut intensities: Vec<f64> = self
+            .peaks()?
+            .into_iter()
+            .map(|(_, intensity)| intensity)
+            .filter(|intensity| *intensity > 0.0)
+            .collect();
+        if intensities.is_empty() {
+            return Ok(0.0);
+        }
+        intensities.sort_by(|a, b| a.total_cmp(b));
+        let middle = intensities.len() / 2;
+        if intensities.len() % 2 == 0 {
+            Ok((intensities[middle - 1] + intensities[middle]) / 2.0)
+        } else {
+            Ok(intensities[middle])
+        }
+    }
+    ///Return the summed intensity of the peaks within `tol` of `target_mz`.
+    pub fn summed_intensity(&self, target_mz: f64, tol: Tolerance) -> Result<f64, MzMLParseError> {
+        Ok(self
+            .peaks()?
+            .into_iter()
+            .filter(|(mz, _)| tol.contains(target_mz, *mz))
+            .map(|(_, intensity)| intensity)
+            .sum())
+    }
+    /**Return the peaks as (m/z, intensity, S/N), with S/N relative to `estimate_noise()`.
+    S/N is 0.0 for every peak when the spectrum has no signal.
+    */
+    pub fn peaks_with_snr(&self) -> Result<Vec<(f64, f64, f64)>, MzMLParseError> {
+        let noise = self.estimate_noise()?;
+        Ok(self
+            .peaks()?
+            .into_iter()
+            .map(|(mz, intensity)| {
+                let snr = if noise > 0.0 { intensity / noise } else { 0.0 };
+                (mz, intensity, snr)
+            })
+            .collect())
+    }
+    ///As `peaks_with_snr()`, dropping peaks with an S/N below `min_snr`.
+    pub fn peaks_above_snr(&self, min_snr: f64) -> Result<Vec<(f64, f64, f64)>, MzMLParseError> {
+        Ok(self
+            .peaks_with_snr()?
+            .into_iter()
+            .filter(|(_, _, snr)| *snr >= min_snr)
+            .collect())
+    }
+    /**Write the spectrum as an MGF `BEGIN IONS` block.
+    PEPMASS and CHARGE come from the first selected ion of the first precursor, and are left out when absent.
+    */
+    pub fn write_mgf<W: Write>(&self, writer: &mut W) -> Result<(), MzMLParseError> {
+        writeln!(writer, "BEGIN IONS")?;
+        writeln!(writer, "TITLE={}", self.id)?;
+        if let Some(precursor) = self
+            .precursor_list
+            .as_ref()
+            .and_then(|list| list.precursors.first())
+        {
+            if let Some(mz) = precursor.selected_ion_mz() {
+                match precursor.selected_ion_intensity() {
+                    Some(intensity) => writeln!(writer, "PEPMASS={} {}", mz, intensity)?,
+                    None => writeln!(writer, "PEPMASS={}", mz)?,
+                }
+            }
+            if let Some(charge) = precursor.charge_state() {
+                let sign = if charge < 0 { '-' } else { '+' };
+                writeln!(writer, "CHARGE={}{}", charge.abs(), sign)?;
+            }
+        }
+        if let Some(rt) = self.rt() {
+            writeln!(writer, "RTINSECONDS={}", rt.get::<second>())?;
+        }
+        for (mz, intensity) in self.peaks()? {
+            writeln!(writer, "{} {}", mz, intensity)?;
+        }
+        writeln!(writer, "END IONS\n")?;
+        Ok(())
+    }
+}
+/**Convert to a SimpleSpectrum, decoding the peaks.
+Fails if the binary data can't be decoded.
+*/
+impl TryFrom<ScanWithData> for SimpleSpectrum {
+    type Error = MzMLParseError;
+    fn try_from(scan: ScanWithData) -> Result<Self, Self::Error> {
+        let (mz, intensity) = scan.peaks()?.into_iter().unzip();
+        let precursor_mz = scan
+            .precursor_list
+            .as_ref()
+            .and_then(|list| list.precursors.first())
+            .and_then(|precursor| {
+                precursor
+                    .selected_ion_mz()
+                    .or_else(|| precursor.isolation_window.target_mz())
+            });
+        Ok(SimpleSpectrum {
+            ms_level: scan.ms_level(),
+            rt_seconds: scan.rt().map(|rt| rt.get::<second>() as f64),
+            mz,
+            intensity,
+            precursor_mz,
+            id: scan.id,
+        })
+    }
+}
+impl ScanWithoutData {
+    fn resolve_param_groups(&mut self, groups: &ReferenceableParamGroupList) {
+        resolve_spectrum_param_groups(
+            groups,
+            &mut self.referenceable_param_group_ref,
+            &mut self.cv_param,
+            &mut self.scan_list,
+            self.precursor_list.as_mut(),
+        );
+    }
+}
+impl MassScan for ScanWithoutData {
+    fn id(&self) -> &str {
+        &self.id
     }
     fn cvs(&self) -> &Vec<ControlledVocabularyParameter> {
         &self.cv_param
     }
-    fn find_cv(&self, name: String) -> Option<&ControlledVocabularyParameter> {
-        self.cv_param.iter().find(|cv| cv.name == name)
+    fn scan_cvs(&self) -> &[ControlledVocabularyParameter] {
+        self.scan_list.first_scan_cvs()
+    }
+    fn user_params(&self) -> &[UserParameter] {
+        &self.user_param
+    }
+    fn scan_user_params(&self) -> &[UserParameter] {
+        self.scan_list.first_scan_user_params()
     }
 }
 impl MassScan for ScanWithData {
-    fn rt(&self) -> Option<uom::si::f32::Time> {
-        let rt_cv = self
-            .scan_list
-            .scan
-            .first()?
-            .cv_param
-            .iter()
-            .find(|c| c.name.find("scan start time").is_some())?;
-        let time: f32 = rt_cv.value.parse().unwrap();
-        let unit_string = rt_cv.unit_name.as_ref()?;
-        match &unit_string[..] {
-            "minute" => Some(Time::new::<minute>(time)),
-            "second" => Some(Time::new::<second>(time)),
-            _ => Some(Time::new::<minute>(time)),
-        }
-    }
-    fn ms_level(&self) -> Option<u16> {
-        self.cv_param
-            .iter()
-            .find(|c| c.name.find("ms level").is_some())?
-            .value
-            .parse()
-            .ok()
+    fn id(&self) -> &str {
+        &self.id
     }
     fn cvs(&self) -> &Vec<ControlledVocabularyParameter> {
         &self.cv_param
     }
-    fn find_cv(&self, name: String) -> Option<&ControlledVocabularyParameter> {
-        self.cv_param.iter().find(|cv| cv.name == name)
+    fn scan_cvs(&self) -> &[ControlledVocabularyParameter] {
+        self.scan_list.first_scan_cvs()
+    }
+    fn user_params(&self) -> &[UserParameter] {
+        &self.user_param
+    }
+    fn scan_user_params(&self) -> &[UserParameter] {
+        self.scan_list.first_scan_user_params()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 struct ScanList {
+    #[serde(rename = "@count", default)]
+    count: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    ///How the scans were combined, e.g. "no combination" (MS:1000795).
+    #[serde(default)]
+    cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_param: Vec<UserParameter>,
     scan: Vec<Scan>,
 }
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+impl ScanList {
+    fn resolve_param_groups(&mut self, groups: &ReferenceableParamGroupList) {
+        groups.expand(&mut self.referenceable_param_group_ref, &mut self.cv_param);
+        for scan in self.scan.iter_mut() {
+            groups.expand(&mut scan.referenceable_param_group_ref, &mut scan.cv_param);
+            for window in scan
+                .scan_window_list
+                .iter_mut()
+                .flat_map(|list| list.scan_windows.iter_mut())
+            {
+                groups.expand(
+                    &mut window.referenceable_param_group_ref,
+                    &mut window.cv_param,
+                );
+            }
+        }
+    }
+    fn first_scan_cvs(&self) -> &[ControlledVocabularyParameter] {
+        self.scan
+            .first()
+            .map(|scan| &scan.cv_param[..])
+            .unwrap_or_default()
+    }
+    fn first_scan_user_params(&self) -> &[UserParameter] {
+        self.scan
+            .first()
+            .map(|scan| &scan.user_param[..])
+            .unwrap_or_default()
+    }
+}
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 struct Scan {
+    #[serde(rename = "@spectrumRef", skip_serializing_if = "Option::is_none")]
+    spectrum_ref: Option<String>,
+    #[serde(rename = "@sourceFileRef", skip_serializing_if = "Option::is_none")]
+    source_file_ref: Option<String>,
+    #[serde(
+        rename = "@externalSpectrumID",
+        skip_serializing_if = "Option::is_none"
+    )]
+    external_spectrum_id: Option<String>,
+    ///The instrument configuration the scan was acquired with, when not the run default.
+    #[serde(
+        rename = "@instrumentConfigurationRef",
+        skip_serializing_if = "Option::is_none"
+    )]
+    instrument_configuration_ref: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
+    cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_param: Vec<UserParameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scan_window_list: Option<ScanWindowList>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ScanWindowList {
+    #[serde(rename = "@count", default)]
+    count: usize,
+    #[serde(rename = "scanWindow")]
+    scan_windows: Vec<ScanWindow>,
+}
+///The m/z range scanned, as "scan window lower limit" (MS:1000501) and "scan window upper limit" (MS:1000500).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ScanWindow {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
     cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_param: Vec<UserParameter>,
 }
 impl Scan {
-    pub fn find_cv(&self, name: String) -> Option<&ControlledVocabularyParameter> {
+    pub fn find_cv(&self, name: &str) -> Option<&ControlledVocabularyParameter> {
         self.cv_param.iter().find(|cv| cv.name == name)
     }
+    pub fn find_cv_by_accession(&self, accession: &str) -> Option<&ControlledVocabularyParameter> {
+        self.cv_param.iter().find(|cv| cv.accession == accession)
+    }
+    pub fn find_user_param(&self, name: &str) -> Option<&UserParameter> {
+        self.user_param.iter().find(|param| param.name == name)
+    }
 }
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 struct BinaryDataArrayList {
     #[serde(rename = "@count")]
     count: u16,
-    #[serde(rename = "binaryDataArray")]
+    #[serde(rename = "binaryDataArray", default)]
     arrays: Vec<BinaryDataArray>,
 }
 impl BinaryDataArrayList {
@@ -401,100 +1734,434 @@ impl BinaryDataArrayList {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 struct BinaryDataArray {
+    ///Number of values in the array, when it differs from the spectrum's defaultArrayLength.
+    #[serde(rename = "@arrayLength", skip_serializing_if = "Option::is_none")]
+    array_length: Option<usize>,
+    #[serde(rename = "@dataProcessingRef", skip_serializing_if = "Option::is_none")]
+    data_processing_ref: Option<String>,
     #[serde(rename = "@encodedLength")]
     encoded_length: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
     cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_param: Vec<UserParameter>,
     binary: String,
 }
+/**How the binary of a BinaryDataArray was encoded.
+mzML specifies little endian, but files from some older tools mark big endian data with a cv parameter.
+*/
+struct ArrayEncoding {
+    zlib: bool,
+    ///Size in bits of each value.
+    data_size: u8,
+    ///Values are integers (MS:1000519, MS:1000522) rather than floats.
+    integer: bool,
+    big_endian: bool,
+}
 impl BinaryDataArray {
-    fn find_zlib_and_float_size(&self) -> (bool, u8) {
-        let mut zlib = false;
-        let mut float_size: u8 = 64;
+    ///Encode `values` uncompressed as 64-bit little endian floats, described by the cv parameter `array_type`.
+    fn encode_f64(
+        values: impl Iterator<Item = f64>,
+        array_type: ControlledVocabularyParameter,
+    ) -> BinaryDataArray {
+        let bytes: Vec<u8> = values.flat_map(f64::to_le_bytes).collect();
+        let binary = general_purpose::STANDARD.encode(bytes);
+        BinaryDataArray {
+            array_length: None,
+            data_processing_ref: None,
+            encoded_length: binary.len(),
+            referenceable_param_group_ref: Vec::new(),
+            cv_param: vec![
+                ms_cv("MS:1000523", "64-bit float", ""),
+                ms_cv("MS:1000576", "no compression", ""),
+                array_type,
+            ],
+            user_param: Vec::new(),
+            binary,
+        }
+    }
+    fn find_encoding(&self) -> ArrayEncoding {
+        let mut encoding = ArrayEncoding {
+            zlib: false,
+            data_size: 64,
+            integer: false,
+            big_endian: false,
+        };
         for param in self.cv_param.iter() {
             let name = &param.name;
-            if let Some(n) = name.find("32-bit float") {
-                float_size = 32;
+            if name.find("16-bit float").is_some() {
+                encoding.data_size = 16;
+            }
+            if name.find("32-bit float").is_some() {
+                encoding.data_size = 32;
+            }
+            if name.find("64-bit float").is_some() {
+                encoding.data_size = 64;
             }
-            if let Some(n) = name.find("64-bit float") {
-                float_size = 64;
+            if name.find("32-bit integer").is_some() || param.accession == "MS:1000519" {
+                encoding.data_size = 32;
+                encoding.integer = true;
             }
-            if let Some(n) = name.find("zlib") {
-                zlib = true;
+            if name.find("64-bit integer").is_some() || param.accession == "MS:1000522" {
+                encoding.data_size = 64;
+                encoding.integer = true;
+            }
+            if name.find("zlib").is_some() {
+                encoding.zlib = true;
+            }
+            if name.to_lowercase().find("big endian").is_some() {
+                encoding.big_endian = true;
             }
         }
-        (zlib, float_size)
+        encoding
+    }
+    /**Return the name of the cv parameter describing the array contents, e.g. "wavelength array".
+    A non-standard data array (MS:1000786) is named by its value. Empty if the array has no such parameter.
+    */
+    fn array_type(&self) -> &str {
+        self.cv_param
+            .iter()
+            .find(|param| param.name.ends_with(" array"))
+            .map(|param| match param.accession.as_str() {
+                "MS:1000786" if !param.value.is_empty() => &param.value,
+                _ => &param.name,
+            })
+            .map_or("", String::as_str)
+    }
+    ///Return the MS-Numpress compression of the array, if any.
+    fn find_numpress(&self) -> Option<Numpress> {
+        self.cv_param
+            .iter()
+            .find_map(|param| Numpress::from_cv_name(&param.name))
     }
     /**Return the decoded data as a Vec.
      */
     fn decode(&self) -> Result<Vec<f64>, MzMLParseError> {
-        let mut binary = base64_decode(self.binary.clone())?;
-        let (zlib, float_size) = self.find_zlib_and_float_size();
+        Ok(self.decode_typed()?.into_f64())
+    }
+    /**Return the decoded data in the type it was stored as.
+    Numpress compressed arrays always decode to `F64`.
+     */
+    fn decode_typed(&self) -> Result<DecodedArray, MzMLParseError> {
+        let mut binary = base64_decode(&self.binary)?;
+        let ArrayEncoding {
+            zlib,
+            data_size,
+            integer,
+            big_endian,
+        } = self.find_encoding();
         if zlib {
             let mut decoder = DeflateDecoder::new(&binary);
             binary = decoder.decode_zlib()?;
         }
-        let mut data = Vec::new();
-        match float_size {
-            64 => {
-                let chunks = binary.chunks(8);
-                for chunk in chunks {
-                    let mut buffer: [u8; 8] = [0 as u8; 8];
-                    if chunk.len() == 8 && buffer.len() == 8 {
-                        for (i, target) in buffer.iter_mut().enumerate() {
-                            *target = chunk[i];
-                        }
-
-                        data.push(f64::from_le_bytes(buffer));
-                    }
+        if let Some(numpress) = self.find_numpress() {
+            return Ok(DecodedArray::F64(numpress.decode(&binary)?));
+        }
+        let data = match (integer, data_size) {
+            (false, 64) => DecodedArray::F64(decode_chunks(&binary, |buffer| {
+                if big_endian {
+                    f64::from_be_bytes(buffer)
+                } else {
+                    f64::from_le_bytes(buffer)
                 }
-            }
-            32 => {
-                let chunks = binary.chunks(4);
-                for chunk in chunks {
-                    let mut buffer: [u8; 4] = [0 as u8; 4];
-                    if chunk.len() == 4 && buffer.len() == 4 {
-                        for (i, target) in buffer.iter_mut().enumerate() {
-                            *target = chunk[i];
-                        }
-
-                        data.push(f32::from_le_bytes(buffer) as f64);
-                    }
+            })),
+            (false, 32) => DecodedArray::F32(decode_chunks(&binary, |buffer| {
+                if big_endian {
+                    f32::from_be_bytes(buffer)
+                } else {
+                    f32::from_le_bytes(buffer)
                 }
-            }
-            _ => panic!("Unknow data size: f_{} for binary array", float_size),
+            })),
+            (true, 64) => DecodedArray::I64(decode_chunks(&binary, |buffer| {
+                if big_endian {
+                    i64::from_be_bytes(buffer)
+                } else {
+                    i64::from_le_bytes(buffer)
+                }
+            })),
+            (true, 32) => DecodedArray::I32(decode_chunks(&binary, |buffer| {
+                if big_endian {
+                    i32::from_be_bytes(buffer)
+                } else {
+                    i32::from_le_bytes(buffer)
+                }
+            })),
+            (_, size) => return Err(MzMLParseError::UnknownDataSize(size)),
         };
         Ok(data)
     }
 }
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+/**Convert each complete `N` byte chunk of `binary`, ignoring a trailing partial chunk.
+The output is allocated once, at its final length.
+*/
+fn decode_chunks<const N: usize, T>(binary: &[u8], convert: impl Fn([u8; N]) -> T) -> Vec<T> {
+    binary
+        .chunks_exact(N)
+        .map(|chunk| {
+            let mut buffer = [0_u8; N];
+            buffer.copy_from_slice(chunk);
+            convert(buffer)
+        })
+        .collect()
+}
+///A decoded binary data array, in the type it was stored as.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodedArray {
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+    I32(Vec<i32>),
+    I64(Vec<i64>),
+}
+impl DecodedArray {
+    pub fn len(&self) -> usize {
+        match self {
+            DecodedArray::F32(data) => data.len(),
+            DecodedArray::F64(data) => data.len(),
+            DecodedArray::I32(data) => data.len(),
+            DecodedArray::I64(data) => data.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    ///Convert the values to f64, widening without loss except for integers beyond 2^53.
+    pub fn into_f64(self) -> Vec<f64> {
+        match self {
+            DecodedArray::F32(data) => data.into_iter().map(|v| v as f64).collect(),
+            DecodedArray::F64(data) => data,
+            DecodedArray::I32(data) => data.into_iter().map(|v| v as f64).collect(),
+            DecodedArray::I64(data) => data.into_iter().map(|v| v as f64).collect(),
+        }
+    }
+    ///Convert the values to f32, narrowing 64 bit values.
+    pub fn into_f32(self) -> Vec<f32> {
+        match self {
+            DecodedArray::F32(data) => data,
+            DecodedArray::F64(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodedArray::I32(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodedArray::I64(data) => data.into_iter().map(|v| v as f32).collect(),
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct PrecursorList {
-    #[serde(rename = "$value")]
+    #[serde(rename = "@count", default)]
+    pub count: usize,
+    #[serde(rename = "precursor")]
     pub precursors: Vec<Precursor>,
 }
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+///The products of an MSn spectrum, recorded by SRM and some DIA files.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ProductList {
+    #[serde(rename = "@count", default)]
+    count: usize,
+    #[serde(rename = "product")]
+    products: Vec<Product>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Product {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    isolation_window: Option<IsolationWindow>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Precursor {
-    #[serde(rename = "@spectrumRef")]
+    #[serde(rename = "@spectrumRef", skip_serializing_if = "Option::is_none")]
     pub reference_spectrum: Option<String>,
+    #[serde(rename = "@sourceFileRef", skip_serializing_if = "Option::is_none")]
+    pub source_file_ref: Option<String>,
+    #[serde(
+        rename = "@externalSpectrumID",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub external_spectrum_id: Option<String>,
     #[serde(default)]
     pub isolation_window: IsolationWindow,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selected_ion_list: Option<SelectedIonList>,
+    #[serde(default)]
+    pub activation: Activation,
 }
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+impl Precursor {
+    fn resolve_param_groups(&mut self, groups: &ReferenceableParamGroupList) {
+        let window = &mut self.isolation_window;
+        groups.expand(
+            &mut window.referenceable_param_group_ref,
+            &mut window.cv_param,
+        );
+        if let Some(selected_ion_list) = self.selected_ion_list.as_mut() {
+            for ion in selected_ion_list.selected_ions.iter_mut() {
+                groups.expand(&mut ion.referenceable_param_group_ref, &mut ion.cv_param);
+            }
+        }
+        let activation = &mut self.activation;
+        groups.expand(
+            &mut activation.referenceable_param_group_ref,
+            &mut activation.cv_param,
+        );
+    }
+    fn first_selected_ion(&self) -> Option<&SelectedIon> {
+        self.selected_ion_list.as_ref()?.selected_ions.first()
+    }
+    ///Return the m/z (MS:1000744) of the first selected ion.
+    pub fn selected_ion_mz(&self) -> Option<f64> {
+        find_cv_value(&self.first_selected_ion()?.cv_param, "MS:1000744")
+    }
+    ///Return the charge state (MS:1000041) of the first selected ion.
+    pub fn charge_state(&self) -> Option<i32> {
+        self.first_selected_ion()?
+            .cv_param
+            .iter()
+            .find(|c| c.accession == "MS:1000041")?
+            .value
+            .parse()
+            .ok()
+    }
+    ///Return the peak intensity (MS:1000042) of the first selected ion.
+    pub fn selected_ion_intensity(&self) -> Option<f64> {
+        find_cv_value(&self.first_selected_ion()?.cv_param, "MS:1000042")
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedIonList {
+    #[serde(rename = "@count", default)]
+    pub count: usize,
+    #[serde(rename = "selectedIon")]
+    pub selected_ions: Vec<SelectedIon>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedIon {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
+    pub cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_param: Vec<UserParameter>,
+}
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct IsolationWindow {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
     pub cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_param: Vec<UserParameter>,
 }
-impl Default for IsolationWindow {
-    fn default() -> Self {
-        IsolationWindow {
-            cv_param: Vec::new(),
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Activation {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
+    pub cv_param: Vec<ControlledVocabularyParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_param: Vec<UserParameter>,
+}
+impl IsolationWindow {
+    ///Return the isolation window target m/z (MS:1000827).
+    pub fn target_mz(&self) -> Option<f64> {
+        find_cv_value(&self.cv_param, "MS:1000827")
+    }
+    ///Return the isolation window lower offset (MS:1000828), in m/z below the target.
+    pub fn lower_offset(&self) -> Option<f64> {
+        find_cv_value(&self.cv_param, "MS:1000828")
+    }
+    ///Return the isolation window upper offset (MS:1000829), in m/z above the target.
+    pub fn upper_offset(&self) -> Option<f64> {
+        find_cv_value(&self.cv_param, "MS:1000829")
+    }
+    /**True if `mz` lies within [target - lower offset, target + upper offset].
+    A missing offset counts as zero; a window without a target contains nothing.
+    */
+    pub fn contains(&self, mz: f64) -> bool {
+        match self.target_mz() {
+            Some(target) => {
+                let lower = target - self.lower_offset().unwrap_or(0.0);
+                let upper = target + self.upper_offset().unwrap_or(0.0);
+                (lower..=upper).contains(&mz)
+            }
+            None => false,
         }
     }
 }
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ReferenceableParamGroupList {
+    #[serde(rename = "@count", default)]
+    count: usize,
+    #[serde(rename = "referenceableParamGroup", default)]
+    groups: Vec<ReferenceableParamGroup>,
+}
+impl ReferenceableParamGroupList {
+    /**Append the cv parameters of each group referenced in `refs` to `cv_param`.
+    Resolved references are removed, so the element reads (and writes back out) the same as if
+    the parameters had been written inline. References to unknown groups are kept.
+    */
+    fn expand(
+        &self,
+        refs: &mut Vec<ReferenceableParamGroupRef>,
+        cv_param: &mut Vec<ControlledVocabularyParameter>,
+    ) {
+        refs.retain(
+            |reference| match self.groups.iter().find(|g| g.id == reference.reference) {
+                Some(group) => {
+                    cv_param.extend(group.cv_param.iter().cloned());
+                    false
+                }
+                None => true,
+            },
+        );
+    }
+}
+///A named set of cv parameters shared between elements through `ReferenceableParamGroupRef`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferenceableParamGroup {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(default)]
+    pub cv_param: Vec<ControlledVocabularyParameter>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferenceableParamGroupRef {
+    #[serde(rename = "@ref")]
+    pub reference: String,
+}
+
+///Return a PSI-MS cv parameter without a unit.
+fn ms_cv(accession: &str, name: &str, value: &str) -> ControlledVocabularyParameter {
+    ControlledVocabularyParameter {
+        cv_ref: String::from("MS"),
+        accession: accession.to_string(),
+        name: name.to_string(),
+        value: value.to_string(),
+        unit_cv_ref: None,
+        unit_accession: None,
+        unit_name: None,
+    }
+}
+
+///Return the value of the cv parameter with the given accession, parsed as a number.
+fn find_cv_value(cv_params: &[ControlledVocabularyParameter], accession: &str) -> Option<f64> {
+    cv_params
+        .iter()
+        .find(|c| c.accession == accession)?
+        .value
+        .parse()
+        .ok()
+}
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -508,16 +2175,17 @@ mod tests {
         // let mut file = tempfile::tempfile().unwrap();
         // std::io::copy(&mut body.as_bytes(), &mut file).expect("failed to copy content");
         let file =
-            std::fs::File::open(std::path::Path::new(r"test_data\small.pwiz.1.1.mzML")).unwrap();
+            std::fs::File::open(std::path::Path::new("test_data/small.pwiz.1.1.mzML")).unwrap();
         let mzml_struct = LazyMzML::new(file).unwrap();
         let intensities: Vec<_> = mzml_struct
             .iter_spectrum()
             .par_bridge()
             .map(|spectrum| {
+                let spectrum = spectrum.unwrap();
                 let time = spectrum.rt().unwrap();
                 let array = spectrum.peaks();
                 match array {
-                    Ok(intensity) => return (intensity[0].0, time),
+                    Ok(intensity) => (intensity[0].0, time),
                     _ => panic!(),
                 }
             })
@@ -535,4 +2203,1367 @@ mod tests {
             println!("{:?}", s.precursor_list);
         }
     }
+    fn small_mzml() -> LazyMzML {
+        let file =
+            std::fs::File::open(std::path::Path::new("test_data/small.pwiz.1.1.mzML")).unwrap();
+        LazyMzML::new(file).unwrap()
+    }
+    #[test]
+    fn simple_spectrum() {
+        let mzml_struct = small_mzml();
+        let scan = mzml_struct.iter_scan().nth(2).unwrap();
+        let mut fetched = mzml_struct.fetch_scan_data(scan).unwrap();
+        let spectrum = SimpleSpectrum::try_from(fetched.clone()).unwrap();
+        assert_eq!(spectrum.id, "controllerType=0 controllerNumber=1 scan=3");
+        assert_eq!(spectrum.ms_level, Some(2));
+        assert_eq!(spectrum.mz.len(), 485);
+        assert_eq!(spectrum.intensity.len(), 485);
+        assert_eq!(spectrum.precursor_mz, Some(810.79));
+        assert!((spectrum.rt_seconds.unwrap() - 0.673).abs() < 1e-3);
+        fetched.binary_data_array_list.arrays[0].binary = String::from("not base64!");
+        assert!(matches!(
+            SimpleSpectrum::try_from(fetched),
+            Err(MzMLParseError::Base64DecodeError(_))
+        ));
+    }
+    #[test]
+    fn cv_accession() {
+        let mzml_struct = small_mzml();
+        let scan = mzml_struct.iter_scan().next().unwrap();
+        let ms_level = scan.find_cv_by_accession("MS:1000511").unwrap();
+        assert_eq!(ms_level.name, "ms level");
+        assert_eq!(ms_level.value, "1");
+        assert!(scan.find_cv_by_accession("MS:0000000").is_none());
+        assert_eq!(scan.find_cv("ms level"), Some(ms_level));
+        let rt = scan.scan_list.scan[0]
+            .find_cv_by_accession("MS:1000016")
+            .unwrap();
+        assert_eq!(scan.scan_list.scan[0].find_cv("scan start time"), Some(rt));
+    }
+    #[test]
+    fn signal_to_noise() {
+        let mzml_struct = small_mzml();
+        let scan = mzml_struct.iter_scan().nth(2).unwrap();
+        let spectrum = mzml_struct.fetch_scan_data(scan).unwrap();
+        let noise = spectrum.estimate_noise().unwrap();
+        assert!(noise > 0.0);
+        let peaks = spectrum.peaks_with_snr().unwrap();
+        assert_eq!(peaks.len(), spectrum.peaks().unwrap().len());
+        for (_, intensity, snr) in peaks.iter() {
+            assert!((snr * noise - intensity).abs() < 1e-6 * intensity.max(1.0));
+        }
+        let filtered = spectrum.peaks_above_snr(1.0).unwrap();
+        assert!(!filtered.is_empty() && filtered.len() < peaks.len());
+        assert!(filtered.iter().all(|(_, _, snr)| *snr >= 1.0));
+    }
+    #[test]
+    fn fetch_errors() {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut original =
+            std::fs::File::open(std::path::Path::new("test_data/small.pwiz.1.1.mzML")).unwrap();
+        std::io::copy(&mut original, &mut file).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mzml_struct = LazyMzML::new(file.try_clone().unwrap()).unwrap();
+
+        let missing = ScanWithoutData {
+            index: 0,
+            id: String::from("not a scan"),
+            default_array_length: 0,
+            referenceable_param_group_ref: Vec::new(),
+            cv_param: Vec::new(),
+            user_param: Vec::new(),
+            precursor_list: None,
+            scan_list: ScanList::default(),
+        };
+        assert!(matches!(
+            mzml_struct.fetch_scan_data(&missing),
+            Err(MzMLParseError::MissingOffset(_))
+        ));
+
+        let last = mzml_struct.iter_scan().last().unwrap();
+        file.set_len(mzml_struct.scan_offsets[&last.id] + 100)
+            .unwrap();
+        assert!(matches!(
+            mzml_struct.fetch_scan_data(last),
+            Err(MzMLParseError::UnexpectedEof(_))
+        ));
+    }
+    #[test]
+    fn spectrum_range() {
+        let mzml_struct = small_mzml();
+        let indices: Vec<_> = mzml_struct
+            .iter_spectrum_range(10, 14)
+            .map(|s| s.unwrap().index)
+            .collect();
+        assert_eq!(indices, vec![10, 11, 12, 13]);
+        assert_eq!(mzml_struct.iter_spectrum_range(40, 100).count(), 8);
+        assert_eq!(mzml_struct.iter_spectrum_range(5, 5).count(), 0);
+    }
+    ///A reader that hands out at most `chunk` bytes per read.
+    struct ShortReader<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+    impl Read for ShortReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+    #[test]
+    fn read_element_short_reads() {
+        let element = "<spectrum id=\"\u{3bc}\"><cvParam/></spectrum>";
+        let document = format!("{}\n<spectrum id=\"next\"></spectrum>", element);
+        for chunk in 1..=document.len() {
+            let reader = ShortReader {
+                data: document.as_bytes(),
+                chunk,
+            };
+            let xml = read_element(reader, "</spectrum>", "test").unwrap();
+            assert_eq!(xml, element, "chunk size {}", chunk);
+        }
+        let reader = ShortReader {
+            data: &element.as_bytes()[..element.len() - 1],
+            chunk: 3,
+        };
+        assert!(matches!(
+            read_element(reader, "</spectrum>", "test"),
+            Err(MzMLParseError::UnexpectedEof(_))
+        ));
+    }
+    #[test]
+    fn acquisition_type() {
+        let mzml_struct = small_mzml();
+        assert_eq!(mzml_struct.acquisition_type(), AcquisitionType::Dda);
+    }
+    ///Build a run from inline spectra, each an ms level and the isolation window target of its precursor.
+    fn inline_run(spectra: &[(u16, Option<f64>)], chromatograms: &str) -> MzMLRun<ScanWithoutData> {
+        let mut xml = format!("<run><spectrumList count=\"{}\">", spectra.len());
+        for (index, (ms_level, target)) in spectra.iter().enumerate() {
+            xml.push_str(&format!(
+                r#"<spectrum index="{index}" id="scan={index}" defaultArrayLength="0">
+                    <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="{ms_level}"/>
+                    <scanList count="1"><scan/></scanList>"#
+            ));
+            if let Some(target) = target {
+                xml.push_str(&format!(
+                    r#"<precursorList count="1"><precursor><isolationWindow>
+                        <cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="{target}"/>
+                    </isolationWindow></precursor></precursorList>"#
+                ));
+            }
+            xml.push_str("</spectrum>");
+        }
+        xml.push_str(&format!("</spectrumList>{}</run>", chromatograms));
+        from_str(&xml).unwrap()
+    }
+    #[test]
+    fn acquisition_type_branches() {
+        const NO_CHROMATOGRAMS: &str = r#"<chromatogramList count="0"/>"#;
+        //Two cycles of the same three windows.
+        let dia: Vec<_> = (0..2)
+            .flat_map(|_| {
+                [
+                    (1, None),
+                    (2, Some(400.0)),
+                    (2, Some(425.0)),
+                    (2, Some(450.0)),
+                ]
+            })
+            .collect();
+        assert_eq!(
+            inline_run(&dia, NO_CHROMATOGRAMS).acquisition_type(),
+            AcquisitionType::Dia
+        );
+        let dda = [
+            (1, None),
+            (2, Some(512.3)),
+            (2, Some(631.8)),
+            (2, Some(702.4)),
+        ];
+        assert_eq!(
+            inline_run(&dda, NO_CHROMATOGRAMS).acquisition_type(),
+            AcquisitionType::Dda
+        );
+        let srm = r#"<chromatogramList count="1">
+            <chromatogram index="0" id="SRM SIC Q1=500 Q3=300">
+                <cvParam cvRef="MS" accession="MS:1001473" name="selected reaction monitoring chromatogram" value=""/>
+            </chromatogram>
+        </chromatogramList>"#;
+        assert_eq!(
+            inline_run(&dda, srm).acquisition_type(),
+            AcquisitionType::Mrm
+        );
+        assert_eq!(
+            inline_run(&[(1, None), (1, None)], NO_CHROMATOGRAMS).acquisition_type(),
+            AcquisitionType::MsOnly
+        );
+        assert_eq!(
+            inline_run(&[], NO_CHROMATOGRAMS).acquisition_type(),
+            AcquisitionType::Unknown
+        );
+        //MSn spectra without isolation windows can't be told apart.
+        assert_eq!(
+            inline_run(&[(1, None), (2, None)], NO_CHROMATOGRAMS).acquisition_type(),
+            AcquisitionType::Unknown
+        );
+    }
+    #[test]
+    fn parallel_fetch() {
+        use rayon::iter::ParallelBridge;
+        use rayon::prelude::ParallelIterator;
+
+        let mzml_struct = small_mzml();
+        let sequential: HashMap<String, Vec<(f64, f64)>> = mzml_struct
+            .iter_spectrum()
+            .map(|s| {
+                let s = s.unwrap();
+                (s.id.clone(), s.peaks().unwrap())
+            })
+            .collect();
+        for _ in 0..4 {
+            let parallel: HashMap<String, Vec<(f64, f64)>> = mzml_struct
+                .iter_spectrum()
+                .par_bridge()
+                .map(|s| {
+                    let s = s.unwrap();
+                    (s.id.clone(), s.peaks().unwrap())
+                })
+                .collect();
+            assert_eq!(parallel, sequential);
+        }
+    }
+    #[test]
+    fn write_round_trip() {
+        let mzml_struct = small_mzml();
+        let mut file = tempfile::tempfile().unwrap();
+        mzml_struct.write_indexed(&mut file).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let written = LazyMzML::new(file).unwrap();
+        assert_eq!(written.iter_scan().count(), 48);
+        for (original, copy) in mzml_struct.iter_spectrum().zip(written.iter_spectrum()) {
+            assert_eq!(original.unwrap(), copy.unwrap());
+        }
+        assert_eq!(written.chromatogram_offsets.len(), 1);
+        assert_eq!(
+            written.mzml_struct.mzml.run.chromatogram_list,
+            mzml_struct.mzml_struct.mzml.run.chromatogram_list
+        );
+    }
+    /**Return the elements of the spectrumList of `text` as canonical events, independent of how the
+    XML was formatted: empty elements are opened and closed, attributes sorted and unescaped.
+    */
+    fn canonical_spectra(text: &str) -> Vec<String> {
+        use quick_xml::events::{BytesStart, Event};
+        fn open(e: &BytesStart) -> String {
+            let mut attributes: Vec<String> = e
+                .attributes()
+                .map(|a| {
+                    let a = a.unwrap();
+                    let value = a.unescape_value().unwrap();
+                    format!("{}={}", String::from_utf8_lossy(a.key.as_ref()), value)
+                })
+                .collect();
+            attributes.sort();
+            format!(
+                "<{} {}>",
+                String::from_utf8_lossy(e.name().as_ref()),
+                attributes.join(" ")
+            )
+        }
+        let mut reader = quick_xml::Reader::from_str(text);
+        reader.trim_text(true);
+        let mut events = Vec::new();
+        let mut in_list = false;
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) if e.name().as_ref() == b"spectrumList" => in_list = true,
+                Event::End(e) if e.name().as_ref() == b"spectrumList" => break,
+                Event::Start(e) if in_list => events.push(open(&e)),
+                Event::Empty(e) if in_list => {
+                    events.push(open(&e));
+                    events.push(format!("</{}>", String::from_utf8_lossy(e.name().as_ref())));
+                }
+                Event::End(e) if in_list => {
+                    events.push(format!("</{}>", String::from_utf8_lossy(e.name().as_ref())))
+                }
+                Event::Text(e) if in_list => events.push(e.unescape().unwrap().into_owned()),
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        events
+    }
+    #[test]
+    fn write_keeps_spectrum_text() {
+        let original = std::fs::read_to_string("test_data/small.pwiz.1.1.mzML").unwrap();
+        let expected = canonical_spectra(&original);
+        let mzml_struct = small_mzml();
+        let mut copy = Vec::new();
+        mzml_struct.write_indexed(&mut copy).unwrap();
+        assert_eq!(
+            canonical_spectra(std::str::from_utf8(&copy).unwrap()),
+            expected
+        );
+        let spectra: Vec<ScanWithData> = mzml_struct.iter_spectrum().map(|s| s.unwrap()).collect();
+        let mut rewritten = Vec::new();
+        mzml_struct
+            .write_indexed_spectra(&mut rewritten, spectra)
+            .unwrap();
+        let rewritten = canonical_spectra(std::str::from_utf8(&rewritten).unwrap());
+        for (position, (rewritten, expected)) in rewritten.iter().zip(expected.iter()).enumerate() {
+            assert_eq!(rewritten, expected, "event {}", position);
+        }
+        assert_eq!(rewritten.len(), expected.len());
+    }
+    #[test]
+    fn write_keeps_attributes() {
+        let xml = format!(
+            r#"<spectrumList count="1">
+                <spectrum index="0" id="scan=2" defaultArrayLength="2" dataProcessingRef="dp" sourceFileRef="sf" spotID="A1">
+                    <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2"/>
+                    <userParam name="voltage" value="3.5" unitCvRef="UO" unitAccession="UO:0000218" unitName="volt" type="xsd:float"/>
+                    <scanList count="1">
+                        <cvParam cvRef="MS" accession="MS:1000795" name="no combination" value=""/>
+                        <scan spectrumRef="scan=1" sourceFileRef="sf" externalSpectrumID="ext" instrumentConfigurationRef="IC2">
+                            <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="1" unitCvRef="UO" unitAccession="UO:0000031" unitName="minute"/>
+                            <scanWindowList count="1">
+                                <scanWindow>
+                                    <cvParam cvRef="MS" accession="MS:1000501" name="scan window lower limit" value="100"/>
+                                    <cvParam cvRef="MS" accession="MS:1000500" name="scan window upper limit" value="2000"/>
+                                </scanWindow>
+                            </scanWindowList>
+                        </scan>
+                    </scanList>
+                    <precursorList count="1">
+                        <precursor spectrumRef="scan=1" sourceFileRef="sf" externalSpectrumID="ext">
+                            <isolationWindow>
+                                <cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="500"/>
+                                <userParam name="window note" value="wide"/>
+                            </isolationWindow>
+                            <activation>
+                                <cvParam cvRef="MS" accession="MS:1000133" name="collision-induced dissociation" value=""/>
+                            </activation>
+                        </precursor>
+                    </precursorList>
+                    <productList count="1">
+                        <product>
+                            <isolationWindow>
+                                <cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="300"/>
+                            </isolationWindow>
+                        </product>
+                    </productList>
+                    <binaryDataArrayList count="2">
+                        <binaryDataArray arrayLength="2" dataProcessingRef="dp" encodedLength="{length}">
+                            <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+                            <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" value=""/>
+                            <userParam name="calibrated" value="true"/>
+                            <binary>{values}</binary>
+                        </binaryDataArray>
+                        <binaryDataArray encodedLength="{length}">
+                            <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+                            <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" value=""/>
+                            <binary>{values}</binary>
+                        </binaryDataArray>
+                    </binaryDataArrayList>
+                </spectrum>
+            </spectrumList>"#,
+            length = 24,
+            values = general_purpose::STANDARD.encode([0_u8; 16]),
+        );
+        let list: SpectrumList<ScanWithData> = from_str(&xml).unwrap();
+        let written = format!(
+            "<spectrumList count=\"1\">{}</spectrumList>",
+            quick_xml::se::to_string(&list.spectra[0]).unwrap()
+        );
+        assert_eq!(canonical_spectra(&written), canonical_spectra(&xml));
+    }
+    #[test]
+    fn write_filtered() {
+        let mzml_struct = small_mzml();
+        let ms2: Vec<ScanWithData> = mzml_struct
+            .iter_spectrum()
+            .map(|s| s.unwrap())
+            .filter(|s| s.ms_level() == Some(2))
+            .collect();
+        let ids: Vec<String> = ms2.iter().map(|s| s.id.clone()).collect();
+        let mut file = tempfile::tempfile().unwrap();
+        mzml_struct.write_indexed_spectra(&mut file, ms2).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let written = LazyMzML::new(file).unwrap();
+        assert_eq!(written.mzml_struct.mzml.run.spectrum_list.count, 34);
+        for (index, spectrum) in written.iter_spectrum().enumerate() {
+            let spectrum = spectrum.unwrap();
+            assert_eq!(spectrum.index, index);
+            assert_eq!(spectrum.id, ids[index]);
+            assert_eq!(spectrum.ms_level(), Some(2));
+        }
+    }
+    #[test]
+    fn swath_window_index() {
+        let mzml_struct = small_mzml();
+        let indices: Vec<_> = mzml_struct
+            .iter_scan()
+            .take(4)
+            .map(|s| s.swath_window_index())
+            .collect();
+        assert_eq!(indices, vec![Some(1), Some(2), Some(3), Some(4)]);
+    }
+    #[test]
+    fn with_rt_and_id() {
+        let mzml_struct = small_mzml();
+        let spectrum = mzml_struct
+            .fetch_scan_data(mzml_struct.iter_scan().next().unwrap())
+            .unwrap();
+        let shifted = spectrum
+            .with_rt(Time::new::<minute>(2.5))
+            .with_id(String::from("scan=1"));
+        assert_eq!(shifted.rt(), Some(Time::new::<minute>(2.5)));
+        assert_eq!(shifted.id, "scan=1");
+        assert_eq!(shifted.peaks().unwrap(), spectrum.peaks().unwrap());
+        assert_eq!(spectrum.id, "controllerType=0 controllerNumber=1 scan=1");
+
+        let mut file = tempfile::tempfile().unwrap();
+        mzml_struct
+            .write_indexed_spectra(&mut file, vec![shifted])
+            .unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let written = LazyMzML::new(file).unwrap();
+        let scan = written.iter_scan().next().unwrap();
+        assert_eq!(scan.id, "scan=1");
+        assert_eq!(scan.rt(), Some(Time::new::<minute>(2.5)));
+    }
+    #[test]
+    fn checksum() {
+        let mzml_struct = small_mzml();
+        assert!(mzml_struct.verify_checksum().unwrap());
+
+        let mut file = tempfile::tempfile().unwrap();
+        mzml_struct.write_indexed(&mut file).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert!(LazyMzML::open_verified(file.try_clone().unwrap()).is_ok());
+
+        //Corrupt one byte of the header, which is copied verbatim.
+        let header = mzml_struct.header().unwrap();
+        let offset = header.find("small_RAW").unwrap();
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.write_all(b"S").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert!(matches!(
+            LazyMzML::open_verified(file),
+            Err(MzMLParseError::ChecksumMismatch(_))
+        ));
+    }
+    #[test]
+    fn precursor_accessors() {
+        let mzml_struct = small_mzml();
+        let scan = mzml_struct.iter_scan().nth(2).unwrap();
+        let precursor = &scan.precursor_list.as_ref().unwrap().precursors[0];
+        assert_eq!(precursor.selected_ion_mz(), Some(810.79));
+        assert_eq!(precursor.charge_state(), None);
+        assert_eq!(precursor.selected_ion_intensity(), None);
+        let window = &precursor.isolation_window;
+        assert_eq!(window.target_mz(), Some(810.79));
+        assert_eq!(window.lower_offset(), Some(0.5));
+        assert_eq!(window.upper_offset(), Some(0.5));
+
+        let precursor: Precursor = from_str(
+            r#"<precursor>
+                <selectedIonList count="1">
+                    <selectedIon>
+                        <cvParam cvRef="MS" accession="MS:1000744" name="selected ion m/z" value="445.34"/>
+                        <cvParam cvRef="MS" accession="MS:1000041" name="charge state" value="2"/>
+                        <cvParam cvRef="MS" accession="MS:1000042" name="peak intensity" value="120053"/>
+                    </selectedIon>
+                </selectedIonList>
+                <activation>
+                    <cvParam cvRef="MS" accession="MS:1000133" name="collision-induced dissociation" value=""/>
+                </activation>
+            </precursor>"#,
+        )
+        .unwrap();
+        assert_eq!(precursor.selected_ion_mz(), Some(445.34));
+        assert_eq!(precursor.charge_state(), Some(2));
+        assert_eq!(precursor.selected_ion_intensity(), Some(120053.0));
+        assert_eq!(precursor.isolation_window.target_mz(), None);
+    }
+    #[test]
+    fn missing_spectrum_index() {
+        let mut spectrum_list: SpectrumList<ScanWithoutData> = from_str(
+            r#"<spectrumList count="3">
+                <spectrum id="a" defaultArrayLength="0">
+                    <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+                    <scanList count="1"><scan><cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="1"/></scan></scanList>
+                </spectrum>
+                <spectrum index="1" id="b" defaultArrayLength="0">
+                    <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+                    <scanList count="1"><scan><cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="2"/></scan></scanList>
+                </spectrum>
+                <spectrum id="c" defaultArrayLength="0">
+                    <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+                    <scanList count="1"><scan><cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="3"/></scan></scanList>
+                </spectrum>
+            </spectrumList>"#,
+        )
+        .unwrap();
+        spectrum_list.assign_missing_indices();
+        let indices: Vec<_> = spectrum_list.spectra.iter().map(|s| s.index).collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+    #[test]
+    fn rt_gaps() {
+        let mzml_struct = small_mzml();
+        assert!(mzml_struct.rt_gaps(Time::new::<minute>(1.0)).is_empty());
+        let gaps = mzml_struct.rt_gaps(Time::new::<second>(2.0));
+        assert!(!gaps.is_empty());
+        for (start, end) in gaps {
+            assert!(end - start > Time::new::<second>(2.0));
+        }
+    }
+    #[test]
+    fn spectrum_rt_range() {
+        let mzml_struct = small_mzml();
+        let start = Time::new::<minute>(0.1);
+        let end = Time::new::<minute>(0.2);
+        let expected = mzml_struct
+            .iter_scan()
+            .filter(|s| s.rt().unwrap() >= start && s.rt().unwrap() < end)
+            .count();
+        assert!(expected > 0 && expected < 48);
+        let spectra: Vec<_> = mzml_struct
+            .iter_spectrum_in_rt_range(start, end)
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(spectra.len(), expected);
+        assert!(spectra
+            .iter()
+            .all(|s| s.rt().unwrap() >= start && s.rt().unwrap() < end));
+    }
+    #[test]
+    fn ms_level_filter() {
+        let mzml_struct = small_mzml();
+        assert_eq!(mzml_struct.count_by_ms_level(1), 14);
+        assert_eq!(mzml_struct.count_by_ms_level(2), 34);
+        assert_eq!(mzml_struct.count_by_ms_level(3), 0);
+        assert!(mzml_struct
+            .iter_scan_by_ms_level(1)
+            .all(|s| s.ms_level() == Some(1)));
+        let spectra: Vec<_> = mzml_struct
+            .iter_spectrum_by_ms_level(2)
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(spectra.len(), 34);
+        assert!(spectra.iter().all(|s| s.ms_level() == Some(2)));
+    }
+    #[test]
+    fn precursor_param_groups() {
+        let groups: ReferenceableParamGroupList = from_str(
+            r#"<referenceableParamGroupList count="2">
+                <referenceableParamGroup id="CID">
+                    <cvParam cvRef="MS" accession="MS:1000133" name="collision-induced dissociation" value=""/>
+                    <cvParam cvRef="MS" accession="MS:1000045" name="collision energy" value="35"/>
+                </referenceableParamGroup>
+                <referenceableParamGroup id="Window">
+                    <cvParam cvRef="MS" accession="MS:1000828" name="isolation window lower offset" value="1.5"/>
+                    <cvParam cvRef="MS" accession="MS:1000829" name="isolation window upper offset" value="1.5"/>
+                </referenceableParamGroup>
+            </referenceableParamGroupList>"#,
+        )
+        .unwrap();
+        let mut precursor: Precursor = from_str(
+            r#"<precursor>
+                <isolationWindow>
+                    <referenceableParamGroupRef ref="Window"/>
+                    <cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="500"/>
+                </isolationWindow>
+                <activation>
+                    <referenceableParamGroupRef ref="CID"/>
+                </activation>
+            </precursor>"#,
+        )
+        .unwrap();
+        assert!(precursor.activation.cv_param.is_empty());
+        assert_eq!(precursor.isolation_window.lower_offset(), None);
+        precursor.resolve_param_groups(&groups);
+        assert_eq!(precursor.activation.cv_param.len(), 2);
+        assert_eq!(
+            precursor.activation.cv_param[0].name,
+            "collision-induced dissociation"
+        );
+        assert!(precursor
+            .activation
+            .referenceable_param_group_ref
+            .is_empty());
+        assert_eq!(precursor.isolation_window.target_mz(), Some(500.0));
+        assert_eq!(precursor.isolation_window.lower_offset(), Some(1.5));
+        assert_eq!(precursor.isolation_window.upper_offset(), Some(1.5));
+        let file_groups = &small_mzml().mzml_struct.mzml.referenceable_param_group_list;
+        assert_eq!(file_groups.groups.len(), 1);
+        assert_eq!(file_groups.groups[0].id, "CommonInstrumentParams");
+    }
+    #[test]
+    fn malformed_rt() {
+        let mzml_struct = small_mzml();
+        let mut spectrum = mzml_struct
+            .fetch_scan_data(mzml_struct.iter_scan().next().unwrap())
+            .unwrap();
+        assert!(spectrum.try_rt().unwrap().is_some());
+        spectrum.scan_list.scan[0].cv_param[0].value = String::from("soon");
+        assert_eq!(spectrum.rt(), None);
+        assert!(matches!(
+            spectrum.try_rt(),
+            Err(MzMLParseError::CvValueParseError(_, _))
+        ));
+        spectrum.scan_list.scan.clear();
+        assert_eq!(spectrum.try_rt().unwrap(), None);
+    }
+    #[test]
+    fn computed_tic() {
+        let mzml_struct = small_mzml();
+        let mut spectrum = mzml_struct
+            .fetch_scan_data(mzml_struct.iter_scan().nth(2).unwrap())
+            .unwrap();
+        assert_eq!(spectrum.total_ion_current(), Some(586279.0));
+        assert_eq!(spectrum.computed_tic().unwrap(), 586279.0);
+        spectrum.cv_param.retain(|c| c.accession != "MS:1000285");
+        assert_eq!(spectrum.total_ion_current(), None);
+        let summed = spectrum.computed_tic().unwrap();
+        assert!((summed - 586279.0).abs() / 586279.0 < 1e-3);
+    }
+    fn cv(name: &str) -> ControlledVocabularyParameter {
+        ControlledVocabularyParameter {
+            cv_ref: String::from("MS"),
+            accession: String::new(),
+            name: name.to_string(),
+            value: String::new(),
+            unit_cv_ref: None,
+            unit_accession: None,
+            unit_name: None,
+        }
+    }
+    fn binary_array(bytes: &[u8], cv_names: &[&str]) -> BinaryDataArray {
+        let binary = general_purpose::STANDARD.encode(bytes);
+        BinaryDataArray {
+            array_length: None,
+            data_processing_ref: None,
+            encoded_length: binary.len(),
+            referenceable_param_group_ref: Vec::new(),
+            cv_param: cv_names.iter().map(|name| cv(name)).collect(),
+            user_param: Vec::new(),
+            binary,
+        }
+    }
+    #[test]
+    fn big_endian() {
+        let values = [1.5_f64, 250.125, -3.0];
+        let big: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        let little: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let array = binary_array(&big, &["64-bit float", "no compression", "big endian"]);
+        assert_eq!(array.decode().unwrap(), values);
+        let array = binary_array(&little, &["64-bit float", "no compression"]);
+        assert_eq!(array.decode().unwrap(), values);
+
+        let values = [1.5_f32, 250.125, -3.0];
+        let big: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        let array = binary_array(&big, &["32-bit float", "Big Endian"]);
+        assert_eq!(array.decode().unwrap(), vec![1.5, 250.125, -3.0]);
+    }
+    #[test]
+    fn integer_arrays() {
+        let values = [7_i32, -2, 1_000_000];
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let array = binary_array(&bytes, &["32-bit integer", "no compression"]);
+        assert_eq!(array.decode().unwrap(), vec![7.0, -2.0, 1_000_000.0]);
+
+        let values = [7_i64, -2, 1 << 40];
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        let mut array = binary_array(&bytes, &["big endian"]);
+        let mut integer = cv("64-bit integer");
+        integer.accession = String::from("MS:1000522");
+        array.cv_param.push(integer);
+        assert_eq!(
+            array.decode().unwrap(),
+            vec![7.0, -2.0, (1_i64 << 40) as f64]
+        );
+
+        let array = binary_array(&[0, 0], &["16-bit float"]);
+        assert!(matches!(
+            array.decode(),
+            Err(MzMLParseError::UnknownDataSize(16))
+        ));
+    }
+    #[test]
+    fn file_spectrum_mode() {
+        let mut mzml_struct = small_mzml();
+        assert_eq!(mzml_struct.file_spectrum_mode(), None);
+        let file_content = &mut mzml_struct.mzml_struct.mzml.file_description.file_content;
+        assert_eq!(file_content.cv_param[0].name, "MSn spectrum");
+        let mut centroid = cv("centroid spectrum");
+        centroid.accession = String::from("MS:1000127");
+        file_content.cv_param.push(centroid);
+        assert_eq!(
+            mzml_struct.file_spectrum_mode(),
+            Some(SpectrumMode::Centroid)
+        );
+        let file_content = &mut mzml_struct.mzml_struct.mzml.file_description.file_content;
+        let mut profile = cv("profile spectrum");
+        profile.accession = String::from("MS:1000128");
+        file_content.cv_param.push(profile);
+        assert_eq!(mzml_struct.file_spectrum_mode(), None);
+    }
+    #[test]
+    fn gzip() {
+        let path = "test_data/small.pwiz.1.1.mzML";
+        let mut encoder =
+            flate2::write::GzEncoder::new(tempfile::tempfile().unwrap(), Default::default());
+        encoder.write_all(&std::fs::read(path).unwrap()).unwrap();
+        let mut file = encoder.finish().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mzml_struct = LazyMzML::new_auto_decompress(file).unwrap();
+        assert_eq!(mzml_struct.iter_scan().count(), 48);
+        assert!(mzml_struct.iter_spectrum().all(|s| s.is_ok()));
+        assert!(mzml_struct.verify_checksum().unwrap());
+
+        let plain = LazyMzML::new_auto_decompress(File::open(path).unwrap()).unwrap();
+        assert_eq!(plain.iter_scan().count(), 48);
+    }
+    #[test]
+    fn demultiplex() {
+        let mzml_struct = small_mzml();
+        let spectrum = mzml_struct
+            .iter_spectrum_by_ms_level(2)
+            .next()
+            .unwrap()
+            .unwrap();
+        let (mz, intensity) = spectrum.peaks().unwrap()[10];
+        let xic = mzml_struct.demultiplex(mz, Tolerance::Ppm(10.0)).unwrap();
+        assert_eq!(xic.len(), 34);
+        assert_eq!(xic[0].0, spectrum.rt().unwrap());
+        assert!(xic[0].1 >= intensity);
+        assert_eq!(
+            xic[0].1,
+            spectrum.summed_intensity(mz, Tolerance::Ppm(10.0)).unwrap()
+        );
+        assert!(xic.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(Tolerance::Da(0.5).contains(100.0, 100.5));
+        assert!(!Tolerance::Ppm(10.0).contains(100.0, 100.01));
+    }
+    #[test]
+    fn summary_statistics() {
+        let mzml_struct = small_mzml();
+        let scan = mzml_struct.iter_scan().next().unwrap();
+        assert_eq!(scan.base_peak_mz(), Some(810.415283203125));
+        assert_eq!(scan.base_peak_intensity(), Some(1471973.875));
+        assert!(scan.total_ion_current().is_some());
+        let tic: Vec<f64> = mzml_struct
+            .iter_scan_by_ms_level(1)
+            .filter_map(|s| s.total_ion_current())
+            .collect();
+        assert_eq!(tic.len(), 14);
+    }
+    #[test]
+    fn fetch_many() {
+        let mzml_struct = small_mzml();
+        let ids: Vec<String> = [40, 3, 17]
+            .iter()
+            .map(|i| mzml_struct.iter_scan().nth(*i).unwrap().id.clone())
+            .chain(std::iter::once(String::from("scan=0")))
+            .collect();
+        let fetched = mzml_struct.fetch_many(&ids);
+        assert_eq!(fetched.len(), 4);
+        for (id, spectrum) in ids.iter().zip(fetched.iter()).take(3) {
+            assert_eq!(&spectrum.as_ref().unwrap().id, id);
+        }
+        assert!(matches!(fetched[3], Err(MzMLParseError::MissingOffset(_))));
+    }
+    #[test]
+    fn polarity() {
+        let mzml_struct = small_mzml();
+        assert_eq!(
+            mzml_struct
+                .iter_scan_by_polarity(Polarity::Positive)
+                .count(),
+            48
+        );
+        assert_eq!(
+            mzml_struct
+                .iter_scan_by_polarity(Polarity::Negative)
+                .count(),
+            0
+        );
+        let mut scan = mzml_struct.iter_scan().next().unwrap().clone();
+        for cv in scan
+            .cv_param
+            .iter_mut()
+            .filter(|cv| cv.accession == "MS:1000130")
+        {
+            cv.accession = String::from("MS:1000129");
+        }
+        assert_eq!(scan.polarity(), Some(Polarity::Negative));
+        scan.cv_param.retain(|cv| cv.accession != "MS:1000129");
+        assert_eq!(scan.polarity(), None);
+    }
+    #[test]
+    fn spectrum_type() {
+        let mzml_struct = small_mzml();
+        let ms1 = mzml_struct.iter_scan_by_ms_level(1).next().unwrap();
+        assert_eq!(ms1.spectrum_type(), Some(SpectrumMode::Profile));
+        let ms2 = mzml_struct.iter_scan_by_ms_level(2).next().unwrap();
+        assert_eq!(ms2.spectrum_type(), Some(SpectrumMode::Centroid));
+        let mut neither = ms2.clone();
+        neither.cv_param.retain(|cv| cv.accession != "MS:1000127");
+        assert_eq!(neither.spectrum_type(), None);
+    }
+    #[test]
+    fn user_params() {
+        let mzml_struct = small_mzml();
+        let name = "[Thermo Trailer Extra]Monoisotopic M/Z:";
+        let scan = mzml_struct.iter_scan().next().unwrap();
+        let param = scan.find_user_param(name).unwrap();
+        assert_eq!(param.value, "810.41522216796875");
+        assert_eq!(param.value_type.as_deref(), Some("xsd:float"));
+        assert_eq!(scan.scan_list.scan[0].find_user_param(name), Some(param));
+        assert!(scan.find_user_param("missing").is_none());
+        let spectrum = mzml_struct.fetch_scan_data(scan).unwrap();
+        assert_eq!(spectrum.find_user_param(name), Some(param));
+    }
+    #[test]
+    fn spectrum_param_groups() {
+        let groups: ReferenceableParamGroupList = from_str(
+            r#"<referenceableParamGroupList count="2">
+                <referenceableParamGroup id="MS1">
+                    <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+                    <cvParam cvRef="MS" accession="MS:1000130" name="positive scan" value=""/>
+                </referenceableParamGroup>
+                <referenceableParamGroup id="Scan">
+                    <cvParam cvRef="MS" accession="MS:1000616" name="preset scan configuration" value="3"/>
+                </referenceableParamGroup>
+            </referenceableParamGroupList>"#,
+        )
+        .unwrap();
+        let mut scan: ScanWithoutData = from_str(
+            r#"<spectrum index="0" id="scan=1" defaultArrayLength="0">
+                <referenceableParamGroupRef ref="MS1"/>
+                <scanList count="1">
+                    <scan>
+                        <referenceableParamGroupRef ref="Scan"/>
+                    </scan>
+                </scanList>
+            </spectrum>"#,
+        )
+        .unwrap();
+        assert_eq!(scan.ms_level(), None);
+        scan.resolve_param_groups(&groups);
+        assert_eq!(scan.ms_level(), Some(1));
+        assert_eq!(scan.polarity(), Some(Polarity::Positive));
+        assert_eq!(scan.swath_window_index(), Some(3));
+        assert_eq!(scan.resolved_cvs().len(), 2);
+        assert!(scan.referenceable_param_group_ref.is_empty());
+    }
+    #[test]
+    fn binary_array_param_groups() {
+        let groups: ReferenceableParamGroupList = from_str(
+            r#"<referenceableParamGroupList count="2">
+                <referenceableParamGroup id="mzArray">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" value=""/>
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+                </referenceableParamGroup>
+                <referenceableParamGroup id="intensityArray">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" value=""/>
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" value=""/>
+                </referenceableParamGroup>
+            </referenceableParamGroupList>"#,
+        )
+        .unwrap();
+        let mz: Vec<u8> = [100.0_f64, 200.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let intensity: Vec<u8> = [1.0_f32, 2.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut spectrum: ScanWithData = from_str(&format!(
+            r#"<spectrum index="0" id="scan=1" defaultArrayLength="2">
+                <scanList count="1"><scan/></scanList>
+                <binaryDataArrayList count="2">
+                    <binaryDataArray encodedLength="24">
+                        <referenceableParamGroupRef ref="mzArray"/>
+                        <binary>{}</binary>
+                    </binaryDataArray>
+                    <binaryDataArray encodedLength="12">
+                        <referenceableParamGroupRef ref="intensityArray"/>
+                        <binary>{}</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+            </spectrum>"#,
+            general_purpose::STANDARD.encode(mz),
+            general_purpose::STANDARD.encode(intensity),
+        ))
+        .unwrap();
+        assert!(matches!(
+            spectrum.peaks(),
+            Err(MzMLParseError::MissingArray(_, "m/z array"))
+        ));
+        spectrum.resolve_param_groups(&groups);
+        assert_eq!(spectrum.peaks().unwrap(), vec![(100.0, 1.0), (200.0, 2.0)]);
+        let arrays = spectrum.decoded_arrays().unwrap();
+        assert!(matches!(arrays[1].1, DecodedArray::F32(_)));
+    }
+    #[test]
+    fn ion_mobility() {
+        use crate::mass_spectrum::IonMobilityKind;
+        let scan: ScanWithoutData = from_str(
+            r#"<spectrum index="0" id="scan=1" defaultArrayLength="0">
+                <cvParam cvRef="MS" accession="MS:1002476" name="ion mobility drift time" value="9.9"/>
+                <scanList count="1">
+                    <scan>
+                        <cvParam cvRef="MS" accession="MS:1002815" name="inverse reduced ion mobility" value="0.85"/>
+                    </scan>
+                </scanList>
+            </spectrum>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            scan.ion_mobility(),
+            Some((0.85, IonMobilityKind::InverseReducedMobility))
+        );
+        let mzml_struct = small_mzml();
+        assert_eq!(mzml_struct.iter_scan().next().unwrap().ion_mobility(), None);
+    }
+    #[test]
+    fn counts() {
+        let mut mzml_struct = small_mzml();
+        assert_eq!(mzml_struct.spectrum_count(), 48);
+        assert_eq!(mzml_struct.chromatogram_count(), 1);
+        assert!(mzml_struct.check_counts().is_ok());
+        mzml_struct.mzml_struct.mzml.run.spectrum_list.spectra.pop();
+        assert!(matches!(
+            mzml_struct.check_counts(),
+            Err(MzMLParseError::CountMismatch(_, 48, 47))
+        ));
+    }
+    #[test]
+    fn large_chromatogram_list() {
+        //Large SRM runs hold more chromatograms than fit in a u16.
+        let list: ChromatogramList = from_str(
+            r#"<chromatogramList count="70000">
+                <chromatogram index="69999" id="SRM SIC Q1=500 Q3=300" defaultArrayLength="0"/>
+            </chromatogramList>"#,
+        )
+        .unwrap();
+        assert_eq!(list.count, 70000);
+        assert_eq!(list.chromatograms[0].index, 69999);
+    }
+    #[test]
+    fn mmap() {
+        let mzml_struct = LazyMzML::open_mmap("test_data/small.pwiz.1.1.mzML").unwrap();
+        let file = small_mzml();
+        for (mapped, read) in mzml_struct.iter_spectrum().zip(file.iter_spectrum()) {
+            assert_eq!(mapped.unwrap(), read.unwrap());
+        }
+        assert!(mzml_struct.verify_checksum().unwrap());
+        assert!(matches!(
+            read_element(
+                mzml_struct.reader_at(u64::MAX),
+                "</spectrum>",
+                "past the end"
+            ),
+            Err(MzMLParseError::UnexpectedEof(_))
+        ));
+    }
+    #[test]
+    fn mgf() {
+        let mzml_struct = small_mzml();
+        let mut mgf = Vec::new();
+        mzml_struct.to_mgf(&mut mgf).unwrap();
+        let mgf = String::from_utf8(mgf).unwrap();
+        assert_eq!(mgf.matches("BEGIN IONS").count(), 34);
+        assert_eq!(mgf.matches("END IONS").count(), 34);
+        let first = mgf.split("END IONS").next().unwrap();
+        let scan = mzml_struct.iter_scan_by_ms_level(2).next().unwrap();
+        assert!(first.contains(&format!("TITLE={}\n", scan.id)));
+        assert!(first.contains("PEPMASS=810.79"));
+        let peaks = mzml_struct.fetch_scan_data(scan).unwrap().peaks().unwrap();
+        let (mz, intensity) = peaks[0];
+        assert!(first.contains(&format!("\n{} {}\n", mz, intensity)));
+    }
+    #[test]
+    fn nearest_scan_by_rt() {
+        let mzml_struct = small_mzml();
+        let scans: Vec<&ScanWithoutData> = mzml_struct.iter_scan().collect();
+        let (first_rt, second_rt) = (scans[0].rt().unwrap(), scans[1].rt().unwrap());
+        let just_after_first = first_rt + (second_rt - first_rt) * 0.25;
+        let nearest = mzml_struct
+            .nearest_scan_by_rt(just_after_first, None)
+            .unwrap();
+        assert_eq!(nearest.id, scans[0].id);
+        for scan in mzml_struct.iter_scan_by_ms_level(2) {
+            let target = scan.rt().unwrap();
+            let expected = mzml_struct
+                .iter_scan_by_ms_level(1)
+                .min_by(|a, b| {
+                    let a = (a.rt().unwrap() - target).abs();
+                    let b = (b.rt().unwrap() - target).abs();
+                    a.value.total_cmp(&b.value)
+                })
+                .unwrap();
+            let nearest = mzml_struct.nearest_scan_by_rt(target, Some(1)).unwrap();
+            assert_eq!(nearest.id, expected.id);
+        }
+        let late = Time::new::<minute>(1000.0);
+        let last = mzml_struct.nearest_scan_by_rt(late, None).unwrap();
+        assert_eq!(last.id, scans.last().unwrap().id);
+        assert!(mzml_struct.nearest_scan_by_rt(late, Some(5)).is_none());
+    }
+    #[test]
+    fn tolerant_base64() {
+        let values = [1.5_f64, 250.125];
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut array = binary_array(&bytes, &["64-bit float", "no compression"]);
+        assert!(array.binary.ends_with("=="));
+        array.binary = array.binary.trim_end_matches('=').to_string();
+        assert_eq!(array.decode().unwrap(), values);
+
+        let mut array = binary_array(&bytes, &["64-bit float", "no compression"]);
+        array.binary.insert_str(8, "\n  ");
+        array.binary.insert(20, '\t');
+        array.binary.push_str("\r\n");
+        assert_eq!(array.decode().unwrap(), values);
+
+        assert_eq!(base64_decode("AQID").unwrap(), vec![1, 2, 3]);
+        assert_eq!(base64_decode("AQ").unwrap(), vec![1]);
+        assert!(matches!(
+            base64_decode("A?=="),
+            Err(MzMLParseError::Base64DecodeError(_))
+        ));
+    }
+    #[test]
+    fn header_metadata() {
+        let mzml_struct = small_mzml();
+        let source_files = mzml_struct.source_files();
+        assert_eq!(source_files.len(), 1);
+        assert_eq!(source_files[0].name, "small.RAW");
+        assert_eq!(source_files[0].location, "file:///.");
+        assert_eq!(
+            source_files[0].checksum(),
+            Some("b43e9286b40e8b5dbc0dfa2e428495769ca96a96")
+        );
+        assert_eq!(mzml_struct.instrument_configurations().len(), 2);
+        let model = mzml_struct.instrument_model().unwrap();
+        assert_eq!(model.name, "LTQ FT");
+        assert_eq!(model.accession, "MS:1000448");
+    }
+    #[test]
+    fn missing_arrays() {
+        let mzml_struct = small_mzml();
+        let mut spectrum = mzml_struct
+            .fetch_scan_data(mzml_struct.iter_scan().next().unwrap())
+            .unwrap();
+        spectrum
+            .binary_data_array_list
+            .arrays
+            .retain(|array| array.cv_param.iter().all(|cv| cv.name != "m/z array"));
+        assert!(matches!(
+            spectrum.peaks(),
+            Err(MzMLParseError::MissingArray(_, "m/z array"))
+        ));
+        let empty: ScanWithData = from_str(
+            r#"<spectrum index="0" id="scan=1" defaultArrayLength="0">
+                <scanList count="1"><scan/></scanList>
+                <binaryDataArrayList count="0"/>
+            </spectrum>"#,
+        )
+        .unwrap();
+        assert_eq!(empty.peaks().unwrap(), Vec::new());
+        assert_eq!(empty.computed_tic().unwrap(), 0.0);
+        //An empty array kept alongside a missing one still means no peaks, a non-empty one doesn't.
+        spectrum.default_array_length = 0;
+        let intensity = spectrum.binary_data_array_list.arrays.remove(0);
+        spectrum.binary_data_array_list.arrays = vec![binary_array(
+            &[],
+            &["64-bit float", "no compression", "intensity array"],
+        )];
+        assert_eq!(spectrum.peaks().unwrap(), Vec::new());
+        spectrum.binary_data_array_list.arrays = vec![intensity];
+        assert!(matches!(
+            spectrum.peaks(),
+            Err(MzMLParseError::MissingArray(_, "m/z array"))
+        ));
+    }
+    #[test]
+    fn decode_typed() {
+        let values = [1.5_f32, 250.125, -3.0];
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let array = binary_array(&bytes, &["32-bit float"]);
+        assert_eq!(
+            array.decode_typed().unwrap(),
+            DecodedArray::F32(values.to_vec())
+        );
+        let bytes: Vec<u8> = [7_i64, -2].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let array = binary_array(&bytes, &["64-bit integer"]);
+        assert_eq!(
+            array.decode_typed().unwrap(),
+            DecodedArray::I64(vec![7, -2])
+        );
+
+        let mzml_struct = small_mzml();
+        let spectrum = mzml_struct
+            .fetch_scan_data(mzml_struct.iter_scan().next().unwrap())
+            .unwrap();
+        match &spectrum.decoded_arrays().unwrap()[..] {
+            [("m/z array", DecodedArray::F64(mz)), ("intensity array", DecodedArray::F32(intensity))] =>
+            {
+                assert_eq!(mz.len(), spectrum.default_array_length);
+                assert_eq!(intensity.len(), spectrum.default_array_length);
+            }
+            arrays => panic!("unexpected arrays {:?}", arrays),
+        }
+        let peaks = spectrum.peaks().unwrap();
+        let peaks_f32 = spectrum.peaks_f32().unwrap();
+        assert_eq!(peaks.len(), peaks_f32.len());
+        for ((mz, intensity), (mz_f32, intensity_f32)) in peaks.iter().zip(peaks_f32.iter()) {
+            assert_eq!(*mz as f32, *mz_f32);
+            assert_eq!(*intensity as f32, *intensity_f32);
+        }
+    }
+    #[test]
+    fn scans_covering_mz() {
+        let mzml_struct = small_mzml();
+        let covering: Vec<&ScanWithoutData> = mzml_struct.scans_covering_mz(811.0).collect();
+        assert!(!covering.is_empty());
+        for scan in covering.iter() {
+            let window = &scan.precursor_list.as_ref().unwrap().precursors[0].isolation_window;
+            let target = window.target_mz().unwrap();
+            assert!((target - 811.0).abs() <= 0.5);
+        }
+        assert_eq!(mzml_struct.scans_covering_mz(10.0).count(), 0);
+        let window = &covering[0].precursor_list.as_ref().unwrap().precursors[0].isolation_window;
+        assert!(window.contains(810.79 - 0.5));
+        assert!(!window.contains(810.79 + 0.6));
+        assert!(!IsolationWindow::default().contains(810.79));
+    }
+    #[test]
+    fn array_length_mismatch() {
+        let mzml_struct = small_mzml();
+        let mut spectrum = mzml_struct
+            .fetch_scan_data(mzml_struct.iter_scan().next().unwrap())
+            .unwrap();
+        let length = spectrum.peaks().unwrap().len();
+        assert_eq!(length, spectrum.default_array_length);
+        spectrum.default_array_length += 1;
+        assert!(matches!(
+            spectrum.peaks(),
+            Err(MzMLParseError::ArrayLengthMismatch(_, m, i, e)) if m == length && i == length && e == length + 1
+        ));
+        spectrum.default_array_length -= 1;
+        let intensity = spectrum
+            .binary_data_array_list
+            .arrays
+            .iter_mut()
+            .find(|array| array.cv_param.iter().any(|cv| cv.name == "intensity array"))
+            .unwrap();
+        let bytes: Vec<u8> = [1.0_f64; 3].iter().flat_map(|v| v.to_le_bytes()).collect();
+        *intensity = binary_array(
+            &bytes,
+            &["64-bit float", "no compression", "intensity array"],
+        );
+        assert!(matches!(
+            spectrum.peaks_f32(),
+            Err(MzMLParseError::ArrayLengthMismatch(_, _, 3, _))
+        ));
+        //Declaring no peaks doesn't hide the peaks that are there.
+        let spectrum: ScanWithData = from_str(&format!(
+            r#"<spectrum index="0" id="scan=1" defaultArrayLength="0">
+                <scanList count="1"><scan/></scanList>
+                <binaryDataArrayList count="2">
+                    <binaryDataArray encodedLength="24">
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+                        <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" value=""/>
+                        <binary>{values}</binary>
+                    </binaryDataArray>
+                    <binaryDataArray encodedLength="24">
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+                        <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" value=""/>
+                        <binary>{values}</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+            </spectrum>"#,
+            values = general_purpose::STANDARD.encode(bytes)
+        ))
+        .unwrap();
+        assert!(matches!(
+            spectrum.peaks(),
+            Err(MzMLParseError::ArrayLengthMismatch(_, 3, 3, 0))
+        ));
+    }
+    #[test]
+    fn array_length_override() {
+        let bytes: Vec<u8> = [1.0_f64; 2].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let spectrum = |intensity_length: usize| -> ScanWithData {
+            from_str(&format!(
+                r#"<spectrum index="0" id="s" defaultArrayLength="3">
+                    <scanList count="1"><scan/></scanList>
+                    <binaryDataArrayList count="2">
+                        <binaryDataArray arrayLength="2" encodedLength="24">
+                            <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+                            <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" value=""/>
+                            <binary>{values}</binary>
+                        </binaryDataArray>
+                        <binaryDataArray arrayLength="{intensity_length}" encodedLength="24">
+                            <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+                            <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" value=""/>
+                            <binary>{values}</binary>
+                        </binaryDataArray>
+                    </binaryDataArrayList>
+                </spectrum>"#,
+                values = general_purpose::STANDARD.encode(&bytes)
+            ))
+            .unwrap()
+        };
+        assert_eq!(spectrum(2).peaks().unwrap(), vec![(1.0, 1.0); 2]);
+        assert_eq!(spectrum(2).peaks_f32().unwrap().len(), 2);
+        assert!(matches!(
+            spectrum(4).peaks(),
+            Err(MzMLParseError::ArrayLengthMismatch(_, 2, 2, 4))
+        ));
+    }
+    #[test]
+    fn large_offsets() {
+        let beyond_u32 = u32::MAX as u64 + 1;
+        let index: Index = from_str(&format!(
+            r#"<index name="spectrum"><offset idRef="scan=1">{}</offset></index>"#,
+            beyond_u32 + 10
+        ))
+        .unwrap();
+        assert_eq!(index.offsets[0].offset, beyond_u32 + 10);
+
+        let mut mzml_struct = small_mzml();
+        let scan = mzml_struct.iter_scan().next().unwrap().clone();
+        mzml_struct.scan_offsets.insert(scan.id.clone(), beyond_u32);
+        assert!(matches!(
+            mzml_struct.fetch_scan_data(&scan),
+            Err(MzMLParseError::UnexpectedEof(_))
+        ));
+    }
+    #[test]
+    fn native_id() {
+        let mzml_struct = small_mzml();
+        let scan = mzml_struct.iter_scan().next().unwrap();
+        assert_eq!(scan.native_id_field("controllerNumber"), Some("1"));
+        assert_eq!(scan.scan_number(), Some(1));
+        let scan: ScanWithoutData = from_str(
+            r#"<spectrum index="0" id="sample=1 period=1 cycle=23 experiment=2" defaultArrayLength="0">
+                <scanList count="1"><scan/></scanList>
+            </spectrum>"#,
+        )
+        .unwrap();
+        assert_eq!(scan.native_id_field("cycle"), Some("23"));
+        assert_eq!(scan.native_id_field("cyc"), None);
+        assert_eq!(scan.scan_number(), None);
+    }
+    #[test]
+    fn binary_arrays() {
+        let mzml_struct = small_mzml();
+        let spectrum = mzml_struct.iter_spectrum().next().unwrap().unwrap();
+        let arrays = spectrum.binary_arrays().unwrap();
+        let names: Vec<&str> = arrays.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["m/z array", "intensity array"]);
+        assert_eq!(arrays[0].1.len(), spectrum.default_array_length);
+
+        let mut wavelength =
+            binary_array(&1.5_f64.to_le_bytes(), &["64-bit float", "no compression"]);
+        wavelength.cv_param.push(ControlledVocabularyParameter {
+            accession: String::from("MS:1000617"),
+            ..cv("wavelength array")
+        });
+        let mut other = binary_array(&2.5_f64.to_le_bytes(), &["64-bit float", "no compression"]);
+        other.cv_param.push(ControlledVocabularyParameter {
+            accession: String::from("MS:1000786"),
+            value: String::from("detector current"),
+            ..cv("non-standard data array")
+        });
+        let mut spectrum = spectrum;
+        spectrum.binary_data_array_list.arrays = vec![wavelength, other];
+        assert_eq!(
+            spectrum.binary_arrays().unwrap(),
+            vec![
+                ("wavelength array", vec![1.5]),
+                ("detector current", vec![2.5])
+            ]
+        );
+    }
+    #[test]
+    fn numpress_arrays() {
+        //Encoded following the encoders of the ms-numpress reference implementation.
+        const LINEAR: [u8; 24] = [
+            0x40, 0xf8, 0x6a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x96, 0x98, 0x00, 0x68, 0x9a,
+            0x98, 0x00, 0x82, 0x81, 0xe4, 0x99, 0xa0, 0x15, 0xd6, 0x60,
+        ];
+        const LINEAR_ZLIB: [u8; 30] = [
+            0x78, 0x9c, 0x73, 0xf8, 0x91, 0xc5, 0x00, 0x02, 0x0d, 0xd3, 0x66, 0x30, 0x64, 0xcc,
+            0x9a, 0xc1, 0xd0, 0xd4, 0xf8, 0x64, 0xe6, 0x02, 0xd1, 0x6b, 0x09, 0x00, 0x64, 0xbb,
+            0x09, 0x56,
+        ];
+        const PIC: [u8; 8] = [0x87, 0x17, 0xf5, 0xc2, 0x13, 0x07, 0x11, 0x10];
+        const SLOF: [u8; 16] = [
+            0x40, 0x8f, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5e, 0x09, 0xfd, 0x1a,
+            0xcc, 0x2d,
+        ];
+        let mz = [100.0, 100.01, 100.02, 200.5, 200.6];
+        for (bytes, compression) in [
+            (&LINEAR[..], "MS-Numpress linear prediction compression"),
+            (
+                &LINEAR_ZLIB[..],
+                "MS-Numpress linear prediction compression followed by zlib compression",
+            ),
+        ] {
+            let array = binary_array(bytes, &["64-bit float", compression, "m/z array"]);
+            let decoded = match array.decode_typed().unwrap() {
+                DecodedArray::F64(decoded) => decoded,
+                decoded => panic!("numpress decoded to {:?}", decoded),
+            };
+            assert_eq!(decoded.len(), mz.len());
+            for (decoded, expected) in decoded.iter().zip(mz) {
+                assert!(
+                    (decoded - expected).abs() < 1e-5,
+                    "{} != {}",
+                    decoded,
+                    expected
+                );
+            }
+        }
+        let array = binary_array(
+            &PIC,
+            &[
+                "32-bit float",
+                "MS-Numpress positive integer compression",
+                "intensity array",
+            ],
+        );
+        assert_eq!(
+            array.decode().unwrap(),
+            vec![0.0, 1.0, 15.0, 300.0, 70000.0]
+        );
+        let array = binary_array(
+            &SLOF,
+            &[
+                "32-bit float",
+                "MS-Numpress short logged float compression",
+                "intensity array",
+            ],
+        );
+        let decoded = array.decode().unwrap();
+        assert_eq!(decoded.len(), 4);
+        for (decoded, expected) in decoded.iter().zip([0.0, 10.0, 1000.0, 123456.0]) {
+            assert!(
+                (decoded - expected).abs() <= expected * 1e-3,
+                "{} != {}",
+                decoded,
+                expected
+            );
+        }
+    }
 }
@@ -1,24 +1,273 @@
+use crate::MzMLParseError;
 use serde::{Deserialize, Serialize};
+use uom::si::f32::Time;
+use uom::si::time::{minute, second};
 
+/**Access to the metadata of a spectrum.
+Every accessor is built on `id()`, `cvs()`, `scan_cvs()`, `user_params()` and `scan_user_params()`,
+so implementors only provide those five.
+*/
 pub trait MassScan {
-    ///Return retention time in minutes.
-    fn rt(&self) -> Option<uom::si::f32::Time>;
-    fn ms_level(&self) -> Option<u16>;
-    fn find_cv(&self, name: String) -> Option<&ControlledVocabularyParameter>;
+    ///Return the native id of the spectrum, the `id` attribute.
+    fn id(&self) -> &str;
+    ///Return the spectrum level cv parameters.
     fn cvs(&self) -> &Vec<ControlledVocabularyParameter>;
+    ///Return the cv parameters of the first scan in the scan list, empty if there is none.
+    fn scan_cvs(&self) -> &[ControlledVocabularyParameter];
+    ///Return the spectrum level user parameters.
+    fn user_params(&self) -> &[UserParameter];
+    ///Return the user parameters of the first scan in the scan list, empty if there is none.
+    fn scan_user_params(&self) -> &[UserParameter];
+    /**Return the spectrum level cv parameters merged with those inherited through referenceableParamGroupRefs.
+    Groups are expanded in place when a spectrum is loaded from a LazyMzML, so every accessor of this trait
+    already sees the inherited parameters and this is `cvs()`.
+    */
+    fn resolved_cvs(&self) -> &[ControlledVocabularyParameter] {
+        self.cvs()
+    }
+    ///Return retention time in minutes.
+    fn rt(&self) -> Option<Time> {
+        self.try_rt().ok().flatten()
+    }
+    /**Return retention time, or an error if the scan start time value can't be parsed.
+    `Ok(None)` when the scan start time, or its unit, is absent.
+    */
+    fn try_rt(&self) -> Result<Option<Time>, MzMLParseError> {
+        let rt_cv = match self
+            .scan_cvs()
+            .iter()
+            .find(|c| c.name.find("scan start time").is_some())
+        {
+            Some(rt_cv) => rt_cv,
+            None => return Ok(None),
+        };
+        let time: f32 = rt_cv.value.parse().map_err(|_| {
+            MzMLParseError::CvValueParseError(rt_cv.name.clone(), rt_cv.value.clone())
+        })?;
+        let unit_string = match rt_cv.unit_name.as_ref() {
+            Some(unit_string) => unit_string,
+            None => return Ok(None),
+        };
+        match &unit_string[..] {
+            "minute" => Ok(Some(Time::new::<minute>(time))),
+            "second" => Ok(Some(Time::new::<second>(time))),
+            _ => Ok(Some(Time::new::<minute>(time))),
+        }
+    }
+    fn ms_level(&self) -> Option<u16> {
+        self.cvs()
+            .iter()
+            .find(|c| c.name.find("ms level").is_some())?
+            .value
+            .parse()
+            .ok()
+    }
+    fn find_cv(&self, name: &str) -> Option<&ControlledVocabularyParameter> {
+        self.cvs().iter().find(|cv| cv.name == name)
+    }
+    ///Return the first spectrum level cv parameter with the given accession, e.g. "MS:1000511".
+    fn find_cv_by_accession(&self, accession: &str) -> Option<&ControlledVocabularyParameter> {
+        self.cvs().iter().find(|cv| cv.accession == accession)
+    }
+    /**Return the first user parameter with the given name, e.g. "[Thermo Trailer Extra]Monoisotopic M/Z:".
+    Spectrum level parameters are searched before those of the first scan.
+    */
+    fn find_user_param(&self, name: &str) -> Option<&UserParameter> {
+        self.user_params()
+            .iter()
+            .chain(self.scan_user_params())
+            .find(|param| param.name == name)
+    }
+    ///Return the total ion current (MS:1000285) recorded for the spectrum.
+    fn total_ion_current(&self) -> Option<f64> {
+        self.find_cv_by_accession("MS:1000285")?.value.parse().ok()
+    }
+    ///Return the base peak m/z (MS:1000504) recorded for the spectrum.
+    fn base_peak_mz(&self) -> Option<f64> {
+        self.find_cv_by_accession("MS:1000504")?.value.parse().ok()
+    }
+    ///Return the base peak intensity (MS:1000505) recorded for the spectrum.
+    fn base_peak_intensity(&self) -> Option<f64> {
+        self.find_cv_by_accession("MS:1000505")?.value.parse().ok()
+    }
+    ///Return whether the spectrum is centroid (MS:1000127) or profile (MS:1000128), None if it is marked as neither.
+    fn spectrum_type(&self) -> Option<SpectrumMode> {
+        SpectrumMode::from_cvs(self.cvs())
+    }
+    ///Return the scan polarity, from the spectrum level positive (MS:1000130) or negative (MS:1000129) scan cv parameter.
+    fn polarity(&self) -> Option<Polarity> {
+        self.cvs().iter().find_map(|cv| match &cv.accession[..] {
+            "MS:1000130" => Some(Polarity::Positive),
+            "MS:1000129" => Some(Polarity::Negative),
+            _ => None,
+        })
+    }
+    /**Return the ion mobility of the scan and what kind of value it is.
+    Read from the cv parameters of the first scan, where mzML records it, not the spectrum level ones.
+    */
+    fn ion_mobility(&self) -> Option<(f64, IonMobilityKind)> {
+        self.scan_cvs().iter().find_map(|cv| {
+            let kind = IonMobilityKind::from_accession(&cv.accession)?;
+            Some((cv.value.parse().ok()?, kind))
+        })
+    }
+    /**Return the preset scan configuration (MS:1000616) of the scan.
+    In SWATH/DIA files this identifies the isolation window exactly, giving an integer key to group MS2 scans by window.
+    */
+    fn swath_window_index(&self) -> Option<u32> {
+        self.scan_cvs()
+            .iter()
+            .find(|c| c.accession == "MS:1000616")?
+            .value
+            .parse()
+            .ok()
+    }
+    /**Return the value of `key` in the native id, e.g. `"1045"` for `scan` in
+    `controllerType=0 controllerNumber=1 scan=1045`.
+    The id is read as space delimited key=value pairs; `None` if `key` isn't one of them.
+    */
+    fn native_id_field(&self, key: &str) -> Option<&str> {
+        self.id().split_ascii_whitespace().find_map(|pair| {
+            let (k, value) = pair.split_once('=')?;
+            (k == key).then_some(value)
+        })
+    }
+    ///Return the scan number, the `scan=` field of the native id.
+    fn scan_number(&self) -> Option<u64> {
+        self.native_id_field("scan")?.parse().ok()
+    }
+}
+///The ion polarity a spectrum was acquired in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    Positive,
+    Negative,
+}
+///The kind of ion mobility value recorded for a scan, see `MassScan::ion_mobility`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IonMobilityKind {
+    ///MS:1002815, inverse reduced ion mobility (1/K0) in Vs/cm^2
+    InverseReducedMobility,
+    ///MS:1002476, ion mobility drift time in milliseconds
+    DriftTime,
+    ///MS:1002954, collisional cross sectional area in square angstroms
+    CollisionalCrossSection,
+}
+impl IonMobilityKind {
+    fn from_accession(accession: &str) -> Option<Self> {
+        match accession {
+            "MS:1002815" => Some(IonMobilityKind::InverseReducedMobility),
+            "MS:1002476" => Some(IonMobilityKind::DriftTime),
+            "MS:1002954" => Some(IonMobilityKind::CollisionalCrossSection),
+            _ => None,
+        }
+    }
+}
+///An m/z tolerance, either absolute or relative to the m/z it's applied at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tolerance {
+    ///Absolute tolerance in Daltons (Th).
+    Da(f64),
+    ///Relative tolerance in parts per million.
+    Ppm(f64),
+}
+impl Tolerance {
+    ///Return the inclusive (lower, upper) m/z bounds around `mz`.
+    pub fn bounds(&self, mz: f64) -> (f64, f64) {
+        let width = match self {
+            Tolerance::Da(da) => *da,
+            Tolerance::Ppm(ppm) => mz * ppm / 1e6,
+        };
+        (mz - width, mz + width)
+    }
+    ///True if `mz` is within tolerance of `target`.
+    pub fn contains(&self, target: f64, mz: f64) -> bool {
+        let (lower, upper) = self.bounds(target);
+        (lower..=upper).contains(&mz)
+    }
+}
+///Whether peaks are stored as centroids or as a continuous profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpectrumMode {
+    ///MS:1000127, centroid spectrum
+    Centroid,
+    ///MS:1000128, profile spectrum
+    Profile,
+}
+impl SpectrumMode {
+    ///Return the mode marked in `cvs`, or None if neither or both are marked.
+    pub fn from_cvs(cvs: &[ControlledVocabularyParameter]) -> Option<Self> {
+        let centroid = cvs.iter().any(|cv| cv.accession == "MS:1000127");
+        let profile = cvs.iter().any(|cv| cv.accession == "MS:1000128");
+        match (centroid, profile) {
+            (true, false) => Some(SpectrumMode::Centroid),
+            (false, true) => Some(SpectrumMode::Profile),
+            _ => None,
+        }
+    }
 }
 pub trait MassSpectrum {
-    fn peaks(&self) -> Result<Vec<(f64, f64)>, crate::MzMLParseError>;
+    fn peaks(&self) -> Result<Vec<(f64, f64)>, MzMLParseError>;
+    /**Return the peaks as f32, halving their memory.
+    The default narrows `peaks()`; implementors holding 32 bit data should decode it directly.
+    */
+    fn peaks_f32(&self) -> Result<Vec<(f32, f32)>, MzMLParseError> {
+        Ok(self
+            .peaks()?
+            .into_iter()
+            .map(|(mz, intensity)| (mz as f32, intensity as f32))
+            .collect())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 #[serde(rename = "cvParam")]
 pub struct ControlledVocabularyParameter {
+    #[serde(rename = "@cvRef", default)]
+    pub cv_ref: String,
+    #[serde(rename = "@accession", default)]
+    pub accession: String,
     #[serde(rename = "@name")]
     pub name: String,
     #[serde(rename = "@value")]
     pub value: String,
-    #[serde(rename = "@unitName")]
+    #[serde(rename = "@unitCvRef", skip_serializing_if = "Option::is_none")]
+    pub unit_cv_ref: Option<String>,
+    #[serde(rename = "@unitAccession", skip_serializing_if = "Option::is_none")]
+    pub unit_accession: Option<String>,
+    #[serde(rename = "@unitName", skip_serializing_if = "Option::is_none")]
     pub unit_name: Option<String>,
 }
+
+///Non-CV metadata held in a `<userParam>`, such as instrument specific trailer values.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename = "userParam")]
+pub struct UserParameter {
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(rename = "@value", default)]
+    pub value: String,
+    #[serde(rename = "@unitCvRef", skip_serializing_if = "Option::is_none")]
+    pub unit_cv_ref: Option<String>,
+    #[serde(rename = "@unitAccession", skip_serializing_if = "Option::is_none")]
+    pub unit_accession: Option<String>,
+    #[serde(rename = "@unitName", skip_serializing_if = "Option::is_none")]
+    pub unit_name: Option<String>,
+    ///The xsd data type of the value, e.g. "xsd:float".
+    #[serde(rename = "@type", skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<String>,
+}
+
+/**A minimal, dependency free spectrum for handing data to other tooling.
+Peaks are held decoded as parallel m/z and intensity vectors.
+*/
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SimpleSpectrum {
+    pub id: String,
+    pub ms_level: Option<u16>,
+    pub rt_seconds: Option<f64>,
+    pub mz: Vec<f64>,
+    pub intensity: Vec<f64>,
+    pub precursor_mz: Option<f64>,
+}
@@ -0,0 +1,220 @@
+/*!Decoders for the MS-Numpress compression schemes.
+
+These follow the reference implementation at <https://github.com/ms-numpress/ms-numpress>.
+The mzML `binary` element holds the numpress byte stream (possibly zlib compressed on top),
+and each decoder turns that stream back into the original values.
+*/
+use crate::MzMLParseError;
+
+///The MS-Numpress compression applied to a binary data array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Numpress {
+    ///MS:1002312, MS-Numpress linear prediction compression
+    Linear,
+    ///MS:1002313, MS-Numpress positive integer compression
+    Pic,
+    ///MS:1002314, MS-Numpress short logged float compression
+    Slof,
+}
+impl Numpress {
+    /**Return the numpress scheme described by a cv name, if any.
+    The zlib layered variants ("... followed by zlib compression") are matched as well,
+    the zlib step is detected separately.
+    */
+    pub(crate) fn from_cv_name(name: &str) -> Option<Self> {
+        if name
+            .find("MS-Numpress linear prediction compression")
+            .is_some()
+        {
+            Some(Numpress::Linear)
+        } else if name
+            .find("MS-Numpress positive integer compression")
+            .is_some()
+        {
+            Some(Numpress::Pic)
+        } else if name
+            .find("MS-Numpress short logged float compression")
+            .is_some()
+        {
+            Some(Numpress::Slof)
+        } else {
+            None
+        }
+    }
+    pub(crate) fn decode(&self, data: &[u8]) -> Result<Vec<f64>, MzMLParseError> {
+        match self {
+            Numpress::Linear => decode_linear(data),
+            Numpress::Pic => decode_pic(data),
+            Numpress::Slof => decode_slof(data),
+        }
+    }
+}
+
+fn decode_fixed_point(data: &[u8]) -> f64 {
+    let mut buffer = [0_u8; 8];
+    buffer.copy_from_slice(&data[..8]);
+    f64::from_be_bytes(buffer)
+}
+
+///Read a little endian 32 bit integer stored at `start`.
+fn decode_u32(data: &[u8], start: usize) -> i64 {
+    let mut buffer = [0_u8; 4];
+    buffer.copy_from_slice(&data[start..start + 4]);
+    u32::from_le_bytes(buffer) as i64
+}
+
+/**Cursor over the half bytes (nibbles) of a numpress stream.
+The high nibble of each byte is read before the low nibble.
+*/
+struct HalfBytes<'a> {
+    data: &'a [u8],
+    position: usize,
+    half: bool,
+}
+impl<'a> HalfBytes<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        HalfBytes {
+            data,
+            position: 0,
+            half: false,
+        }
+    }
+    fn next_half_byte(&mut self) -> Result<u32, MzMLParseError> {
+        let byte = *self
+            .data
+            .get(self.position)
+            .ok_or(MzMLParseError::NumpressDecodeError("truncated integer"))?;
+        if self.half {
+            self.position += 1;
+            self.half = false;
+            Ok((byte & 0xf) as u32)
+        } else {
+            self.half = true;
+            Ok((byte >> 4) as u32)
+        }
+    }
+    ///True once every meaningful half byte has been read, ignoring a trailing zero pad.
+    fn is_finished(&self) -> bool {
+        if self.position >= self.data.len() {
+            return true;
+        }
+        self.half && self.position == self.data.len() - 1 && self.data[self.position] & 0xf == 0
+    }
+    /**Decode one variable length integer.
+    The first half byte is a header: 0-8 gives a count of leading zero half bytes,
+    9-15 gives a count (minus 8) of leading 0xf half bytes. The remaining half bytes follow,
+    least significant first.
+    */
+    fn next_int(&mut self) -> Result<u32, MzMLParseError> {
+        let head = self.next_half_byte()?;
+        let mut result: u32 = 0;
+        let n = if head <= 8 {
+            head
+        } else {
+            let n = head - 8;
+            for i in 0..n {
+                result |= 0xf000_0000 >> (4 * i);
+            }
+            n
+        };
+        for i in n..8 {
+            result |= self.next_half_byte()? << ((i - n) * 4);
+        }
+        Ok(result)
+    }
+}
+
+fn decode_linear(data: &[u8]) -> Result<Vec<f64>, MzMLParseError> {
+    if data.len() < 8 {
+        return Err(MzMLParseError::NumpressDecodeError(
+            "linear stream is missing its fixed point",
+        ));
+    }
+    if data.len() == 8 {
+        return Ok(Vec::new());
+    }
+    let fixed_point = decode_fixed_point(data);
+    if data.len() < 12 {
+        return Err(MzMLParseError::NumpressDecodeError(
+            "linear stream is missing its first value",
+        ));
+    }
+    let mut previous = decode_u32(data, 8);
+    let mut result = vec![previous as f64 / fixed_point];
+    if data.len() == 12 {
+        return Ok(result);
+    }
+    if data.len() < 16 {
+        return Err(MzMLParseError::NumpressDecodeError(
+            "linear stream is missing its second value",
+        ));
+    }
+    let mut current = decode_u32(data, 12);
+    result.push(current as f64 / fixed_point);
+    let mut half_bytes = HalfBytes::new(&data[16..]);
+    while !half_bytes.is_finished() {
+        let diff = half_bytes.next_int()? as i32 as i64;
+        let next = 2 * current - previous + diff;
+        result.push(next as f64 / fixed_point);
+        previous = current;
+        current = next;
+    }
+    Ok(result)
+}
+
+fn decode_pic(data: &[u8]) -> Result<Vec<f64>, MzMLParseError> {
+    let mut result = Vec::new();
+    let mut half_bytes = HalfBytes::new(data);
+    while !half_bytes.is_finished() {
+        result.push(half_bytes.next_int()? as f64);
+    }
+    Ok(result)
+}
+
+fn decode_slof(data: &[u8]) -> Result<Vec<f64>, MzMLParseError> {
+    if data.len() < 8 {
+        return Err(MzMLParseError::NumpressDecodeError(
+            "short logged float stream is missing its fixed point",
+        ));
+    }
+    let fixed_point = decode_fixed_point(data);
+    Ok(data[8..]
+        .chunks_exact(2)
+        .map(|chunk| {
+            let value = u16::from_le_bytes([chunk[0], chunk[1]]);
+            (value as f64 / fixed_point).exp() - 1.0
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pic() {
+        let decoded = Numpress::Pic.decode(&[0x71, 0x72, 0x73]).unwrap();
+        assert_eq!(decoded, vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn linear() {
+        let mut data = 10.0_f64.to_be_bytes().to_vec();
+        data.extend_from_slice(&10_u32.to_le_bytes());
+        data.extend_from_slice(&20_u32.to_le_bytes());
+        //Third value is a perfect extrapolation (diff of 0), fourth is off by -1, padded.
+        data.extend_from_slice(&[0x8f, 0xf0]);
+        let decoded = Numpress::Linear.decode(&data).unwrap();
+        assert_eq!(decoded, vec![1.0, 2.0, 3.0, 3.9]);
+    }
+
+    #[test]
+    fn slof() {
+        let mut data = 100.0_f64.to_be_bytes().to_vec();
+        data.extend_from_slice(&0_u16.to_le_bytes());
+        data.extend_from_slice(&100_u16.to_le_bytes());
+        let decoded = Numpress::Slof.decode(&data).unwrap();
+        assert_eq!(decoded[0], 0.0);
+        assert!((decoded[1] - (std::f64::consts::E - 1.0)).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,160 @@
+/*!Streaming spectra out of mzML without an index.
+
+`stream_spectra` pulls XML events from any `BufRead`, holding a single `<spectrum>` element in
+memory at a time, so indexed and non-indexed files of any size can be processed front to back.
+*/
+use crate::{MzMLParseError, ReferenceableParamGroupList, ScanWithData, MISSING_INDEX};
+use quick_xml::de::{from_str, DeError};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::io::BufRead;
+
+/**Iterate the spectra of an mzML document read from `reader`, in file order.
+Param group references are resolved against the document's referenceableParamGroupList.
+A spectrum that can't be deserialized is yielded as an `Err` and streaming continues;
+malformed XML or a truncated document ends the stream after its error.
+*/
+pub fn stream_spectra<R: BufRead>(
+    reader: R,
+) -> impl Iterator<Item = Result<ScanWithData, MzMLParseError>> {
+    SpectrumStream {
+        reader: Reader::from_reader(reader),
+        buffer: Vec::new(),
+        param_groups: ReferenceableParamGroupList::default(),
+        position: 0,
+        finished: false,
+    }
+}
+
+struct SpectrumStream<R: BufRead> {
+    reader: Reader<R>,
+    buffer: Vec<u8>,
+    param_groups: ReferenceableParamGroupList,
+    ///Position of the next spectrum in the spectrum list.
+    position: usize,
+    finished: bool,
+}
+
+pub(crate) fn xml_error(error: quick_xml::Error) -> MzMLParseError {
+    DeError::from(error).into()
+}
+
+///Read the rest of the element opened by `start` from `reader`, returning the whole element as text.
+pub(crate) fn capture_element<R: BufRead>(
+    reader: &mut Reader<R>,
+    start: BytesStart<'static>,
+) -> Result<String, MzMLParseError> {
+    let name = start.name().as_ref().to_vec();
+    let mut writer = Writer::new(Vec::new());
+    writer.write_event(Event::Start(start)).map_err(xml_error)?;
+    let mut depth = 1;
+    let mut buffer = Vec::new();
+    while depth > 0 {
+        buffer.clear();
+        let event = reader.read_event_into(&mut buffer).map_err(xml_error)?;
+        match &event {
+            Event::Start(e) if e.name().as_ref() == name => depth += 1,
+            Event::End(e) if e.name().as_ref() == name => depth -= 1,
+            Event::Eof => {
+                return Err(MzMLParseError::UnexpectedEof(
+                    String::from_utf8_lossy(&name).into_owned(),
+                ))
+            }
+            _ => {}
+        }
+        writer.write_event(&event).map_err(xml_error)?;
+    }
+    let xml = String::from_utf8(writer.into_inner()).map_err(|e| e.utf8_error())?;
+    Ok(xml)
+}
+
+impl<R: BufRead> SpectrumStream<R> {
+    fn parse_spectrum(&mut self, xml: &str) -> Result<ScanWithData, MzMLParseError> {
+        let mut spectrum: ScanWithData = from_str(xml)?;
+        if spectrum.index == MISSING_INDEX {
+            spectrum.index = self.position;
+        }
+        spectrum.resolve_param_groups(&self.param_groups);
+        Ok(spectrum)
+    }
+}
+
+impl<R: BufRead> Iterator for SpectrumStream<R> {
+    type Item = Result<ScanWithData, MzMLParseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            self.buffer.clear();
+            let start = match self.reader.read_event_into(&mut self.buffer) {
+                Ok(Event::Start(start))
+                    if start.name().as_ref() == b"spectrum"
+                        || start.name().as_ref() == b"referenceableParamGroupList" =>
+                {
+                    start.into_owned()
+                }
+                Ok(Event::Eof) => {
+                    self.finished = true;
+                    return None;
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(xml_error(e)));
+                }
+            };
+            let is_spectrum = start.name().as_ref() == b"spectrum";
+            let xml = match capture_element(&mut self.reader, start) {
+                Ok(xml) => xml,
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            };
+            if is_spectrum {
+                let spectrum = self.parse_spectrum(&xml);
+                self.position += 1;
+                return Some(spectrum);
+            }
+            match from_str(&xml) {
+                Ok(param_groups) => self.param_groups = param_groups,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LazyMzML;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    const SMALL: &str = "test_data/small.pwiz.1.1.mzML";
+
+    #[test]
+    fn stream_matches_index() {
+        let file = File::open(SMALL).unwrap();
+        let streamed: Vec<ScanWithData> = stream_spectra(BufReader::new(file))
+            .map(|s| s.unwrap())
+            .collect();
+        let mzml = LazyMzML::new(File::open(SMALL).unwrap()).unwrap();
+        assert_eq!(streamed.len(), 48);
+        for (streamed, fetched) in streamed.iter().zip(mzml.iter_spectrum()) {
+            assert_eq!(streamed, &fetched.unwrap());
+        }
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let text = std::fs::read_to_string(SMALL).unwrap();
+        let cut = text.find("</spectrum>").unwrap() + 100;
+        let mut spectra = stream_spectra(&text.as_bytes()[..cut]);
+        assert!(spectra.next().unwrap().is_ok());
+        assert!(matches!(
+            spectra.next(),
+            Some(Err(MzMLParseError::UnexpectedEof(_)))
+        ));
+        assert!(spectra.next().is_none());
+    }
+}
@@ -0,0 +1,434 @@
+/*!Whole file validation of indexed mzML.
+
+`validate_file` streams the file once, front to back, holding a single spectrum in memory at a time
+and hashing the bytes as they are read. It checks the file against the basic invariants a reader
+relies on, and reports every problem found rather than stopping at the first.
+*/
+use crate::mass_spectrum::MassSpectrum;
+use crate::stream::{capture_element, xml_error};
+use crate::{writer, MzMLParseError, ReferenceableParamGroupList, ScanWithData};
+use quick_xml::de::from_str;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+///A single violation found by `validate_file`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationProblem {
+    ///The count attribute of a spectrumList or chromatogramList differs from the elements it holds.
+    CountMismatch {
+        list: &'static str,
+        declared: usize,
+        found: usize,
+    },
+    ///Two spectra, or two chromatograms, share an id.
+    DuplicateId(String),
+    ///The file has spectra or chromatograms but no index of them, named "spectrum" or "chromatogram".
+    MissingIndex(&'static str),
+    ///A spectrum or chromatogram has no entry in the index.
+    MissingOffset(String),
+    ///An index offset does not point at the element it names.
+    UnresolvedOffset { id: String, reason: String },
+    ///A spectrum could not be deserialized.
+    InvalidElement { id: String, reason: String },
+    ///A spectrum with peaks lacks its m/z or intensity array.
+    MissingArray { id: String, array: &'static str },
+    ///A binary array of a spectrum could not be decoded.
+    DecodeError { id: String, reason: String },
+    ///The m/z and intensity arrays of a spectrum don't both decode to their declared length,
+    ///the array's arrayLength or else the spectrum's defaultArrayLength.
+    ArrayLengthMismatch {
+        id: String,
+        mz: usize,
+        intensity: usize,
+        expected: usize,
+    },
+    ///The file has no `<fileChecksum>`.
+    MissingChecksum,
+    ///The file does not match its `<fileChecksum>`.
+    ChecksumMismatch,
+}
+
+///Every problem found by `validate_file`, in the order they were found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    pub problems: Vec<ValidationProblem>,
+}
+impl ValidationReport {
+    ///True if no problems were found.
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/**Validate the indexed mzML file at `path`.
+Checks that list counts match, ids are unique, every element is indexed at its actual offset,
+every spectrum has m/z and intensity arrays decoding to their declared length, and the checksum matches.
+An error is returned if the file can't be read or isn't well formed XML, problems with its content are reported.
+*/
+pub fn validate_file<P: AsRef<Path>>(path: P) -> Result<ValidationReport, MzMLParseError> {
+    let mut reader = Reader::from_reader(HashingReader::new(File::open(path)?));
+    let mut problems = Vec::new();
+    let mut param_groups = ReferenceableParamGroupList::default();
+    let mut spectra = ElementList::new("spectrumList");
+    let mut chromatograms = ElementList::new("chromatogramList");
+    let mut indexes: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    let mut index = None;
+    let mut offset_id = None;
+    let mut checksum = None;
+    let mut hashed = None;
+    let mut buffer = Vec::new();
+    loop {
+        let position = reader.buffer_position() as u64;
+        buffer.clear();
+        match reader.read_event_into(&mut buffer).map_err(xml_error)? {
+            Event::Start(start) => match start.name().as_ref() {
+                b"spectrumList" => spectra.declared = count(&start),
+                b"chromatogramList" => chromatograms.declared = count(&start),
+                b"referenceableParamGroupList" => {
+                    let xml = capture_element(&mut reader, start.into_owned())?;
+                    param_groups = from_str(&xml)?;
+                }
+                b"spectrum" => {
+                    let id = attribute(&start, "id").unwrap_or_default();
+                    let xml = capture_element(&mut reader, start.into_owned())?;
+                    if spectra.insert(&id, position) {
+                        problems.push(ValidationProblem::DuplicateId(id.clone()));
+                    }
+                    match from_str::<ScanWithData>(&xml) {
+                        Ok(mut spectrum) => {
+                            spectrum.resolve_param_groups(&param_groups);
+                            check_arrays(&spectrum, &mut problems);
+                        }
+                        Err(e) => problems.push(ValidationProblem::InvalidElement {
+                            id,
+                            reason: e.to_string(),
+                        }),
+                    }
+                }
+                b"chromatogram" => {
+                    let id = attribute(&start, "id").unwrap_or_default();
+                    let end = start.to_end().into_owned();
+                    reader
+                        .read_to_end_into(end.name(), &mut Vec::new())
+                        .map_err(xml_error)?;
+                    if chromatograms.insert(&id, position) {
+                        problems.push(ValidationProblem::DuplicateId(id));
+                    }
+                }
+                b"index" => index = attribute(&start, "name"),
+                b"offset" => offset_id = attribute(&start, "idRef"),
+                b"fileChecksum" => hashed = Some(reader.get_ref().hasher.clone()),
+                _ => {}
+            },
+            Event::Empty(start) => match start.name().as_ref() {
+                b"spectrumList" => spectra.declared = count(&start),
+                b"chromatogramList" => chromatograms.declared = count(&start),
+                b"index" => {
+                    if let Some(name) = attribute(&start, "name") {
+                        indexes.entry(name).or_default();
+                    }
+                }
+                _ => {}
+            },
+            Event::Text(text) => {
+                let text = text.unescape().map_err(xml_error)?;
+                if let Some(id) = offset_id.take() {
+                    if let Some(name) = &index {
+                        let offset = text.trim().to_string();
+                        indexes.entry(name.clone()).or_default().push((id, offset));
+                    }
+                } else if hashed.is_some() && checksum.is_none() {
+                    checksum = Some(text.trim().to_string());
+                }
+            }
+            Event::End(end) => match end.name().as_ref() {
+                b"spectrumList" => spectra.check_count(&mut problems),
+                b"chromatogramList" => chromatograms.check_count(&mut problems),
+                b"index" => index = None,
+                b"offset" => offset_id = None,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    spectra.check_index(indexes.get("spectrum"), "spectrum", &mut problems);
+    chromatograms.check_index(indexes.get("chromatogram"), "chromatogram", &mut problems);
+
+    match (hashed, checksum) {
+        (Some(hasher), Some(checksum)) => {
+            let digest = writer::to_hex(&hasher.finalize());
+            if !digest.eq_ignore_ascii_case(&checksum) {
+                problems.push(ValidationProblem::ChecksumMismatch);
+            }
+        }
+        (Some(_), None) => problems.push(ValidationProblem::ChecksumMismatch),
+        (None, _) => problems.push(ValidationProblem::MissingChecksum),
+    }
+    Ok(ValidationReport { problems })
+}
+
+///The spectra or chromatograms of a list, with the offset each starts at, in file order.
+struct ElementList {
+    list: &'static str,
+    declared: usize,
+    offsets: Vec<(String, u64)>,
+    ids: HashSet<String>,
+}
+impl ElementList {
+    fn new(list: &'static str) -> Self {
+        ElementList {
+            list,
+            declared: 0,
+            offsets: Vec::new(),
+            ids: HashSet::new(),
+        }
+    }
+    ///Record an element starting at `position`, returning true if its id was already seen.
+    fn insert(&mut self, id: &str, position: u64) -> bool {
+        self.offsets.push((id.to_string(), position));
+        !self.ids.insert(id.to_string())
+    }
+    fn check_count(&self, problems: &mut Vec<ValidationProblem>) {
+        if self.declared != self.offsets.len() {
+            problems.push(ValidationProblem::CountMismatch {
+                list: self.list,
+                declared: self.declared,
+                found: self.offsets.len(),
+            });
+        }
+    }
+    ///Check the `(id, offset)` entries of the index named `name` against the elements of the list.
+    fn check_index(
+        &self,
+        index: Option<&Vec<(String, String)>>,
+        name: &'static str,
+        problems: &mut Vec<ValidationProblem>,
+    ) {
+        let index = match index {
+            Some(index) => index,
+            None if self.offsets.is_empty() => return,
+            None => return problems.push(ValidationProblem::MissingIndex(name)),
+        };
+        let indexed: HashSet<&String> = index.iter().map(|(id, _)| id).collect();
+        for (id, _) in self.offsets.iter() {
+            if !indexed.contains(id) {
+                problems.push(ValidationProblem::MissingOffset(id.clone()));
+            }
+        }
+        let mut positions = HashMap::new();
+        for (id, position) in self.offsets.iter() {
+            positions.entry(id).or_insert(*position);
+        }
+        for (id, offset) in index.iter() {
+            let reason = match (offset.parse::<u64>(), positions.get(id)) {
+                (Err(e), _) => format!("unparsable offset {}: {}", offset, e),
+                (Ok(_), None) => format!("no {} has this id", name),
+                (Ok(offset), Some(&position)) if offset != position => {
+                    format!("offset {} but the {} starts at {}", offset, name, position)
+                }
+                _ => continue,
+            };
+            problems.push(ValidationProblem::UnresolvedOffset {
+                id: id.clone(),
+                reason,
+            });
+        }
+    }
+}
+
+///A BufRead hashing every byte as it is consumed, so the checksum is computed in the same pass as parsing.
+struct HashingReader<R: Read> {
+    inner: BufReader<R>,
+    hasher: Sha1,
+}
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner: BufReader::new(inner),
+            hasher: Sha1::new(),
+        }
+    }
+}
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.fill_buf()?;
+        let length = data.len().min(buf.len());
+        buf[..length].copy_from_slice(&data[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+impl<R: Read> BufRead for HashingReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.hasher.update(&self.inner.buffer()[..amt]);
+        self.inner.consume(amt);
+    }
+}
+
+fn attribute(start: &BytesStart, name: &str) -> Option<String> {
+    let attribute = start.try_get_attribute(name).ok()??;
+    Some(attribute.unescape_value().ok()?.into_owned())
+}
+
+///The count attribute of a list, an absent or unparsable count reads as 0.
+fn count(start: &BytesStart) -> usize {
+    attribute(start, "count")
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0)
+}
+
+///Check the peak arrays of `spectrum`, those of a spectrum declaring no peaks may be left out.
+fn check_arrays(spectrum: &ScanWithData, problems: &mut Vec<ValidationProblem>) {
+    let id = spectrum.id.clone();
+    let problem = match spectrum.peaks() {
+        Ok(_) => return,
+        Err(MzMLParseError::MissingArray(_, array)) => {
+            ValidationProblem::MissingArray { id, array }
+        }
+        Err(MzMLParseError::ArrayLengthMismatch(_, mz, intensity, expected)) => {
+            ValidationProblem::ArrayLengthMismatch {
+                id,
+                mz,
+                intensity,
+                expected,
+            }
+        }
+        Err(e) => ValidationProblem::DecodeError {
+            id,
+            reason: e.to_string(),
+        },
+    };
+    problems.push(problem);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const SMALL: &str = "test_data/small.pwiz.1.1.mzML";
+
+    ///Validate `SMALL` with the same length substitution `from` -> `to` applied.
+    fn validate_edited(from: &str, to: &str) -> ValidationReport {
+        assert_eq!(from.len(), to.len());
+        let text = std::fs::read_to_string(SMALL).unwrap();
+        assert!(text.contains(from));
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(text.replacen(from, to, 1).as_bytes())
+            .unwrap();
+        validate_file(file.path()).unwrap()
+    }
+
+    #[test]
+    fn valid_file() {
+        let report = validate_file(SMALL).unwrap();
+        assert!(report.is_valid(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        //Same length edits keep every offset valid.
+        let report = validate_edited("<spectrumList count=\"48\"", "<spectrumList count=\"47\"");
+        assert_eq!(
+            report.problems,
+            vec![
+                ValidationProblem::CountMismatch {
+                    list: "spectrumList",
+                    declared: 47,
+                    found: 48,
+                },
+                ValidationProblem::ChecksumMismatch,
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_index() {
+        let report = validate_edited("<index name=\"spectrum\">", "<index name=\"spectrxm\">");
+        assert_eq!(
+            report.problems,
+            vec![
+                ValidationProblem::MissingIndex("spectrum"),
+                ValidationProblem::ChecksumMismatch,
+            ]
+        );
+        //LazyMzML opens the same file, failing only to fetch the unindexed spectra.
+        let text = std::fs::read_to_string(SMALL).unwrap().replacen(
+            "<index name=\"spectrum\">",
+            "<index name=\"spectrxm\">",
+            1,
+        );
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+        let mzml = crate::LazyMzML::new(File::open(file.path()).unwrap()).unwrap();
+        assert!(matches!(
+            mzml.iter_spectrum().next(),
+            Some(Err(MzMLParseError::MissingOffset(_)))
+        ));
+    }
+
+    #[test]
+    fn wrong_offset() {
+        let text = std::fs::read_to_string(SMALL).unwrap();
+        let start = text.find("<offset idRef=\"").unwrap();
+        let offset = &text[text[start..].find('>').unwrap() + start + 1..];
+        let offset = &offset[..offset.find('<').unwrap()];
+        let wrong = (offset.parse::<u64>().unwrap() + 1).to_string();
+        let report = validate_edited(
+            &format!("\">{}</offset>", offset),
+            &format!("\">{}</offset>", wrong),
+        );
+        assert!(matches!(
+            report.problems.as_slice(),
+            [
+                ValidationProblem::UnresolvedOffset { .. },
+                ValidationProblem::ChecksumMismatch,
+            ]
+        ));
+    }
+
+    #[test]
+    fn array_checks() {
+        let text = std::fs::read_to_string(SMALL).unwrap();
+        let start = text.find("<spectrum ").unwrap();
+        let end = text.find("</spectrum>").unwrap() + "</spectrum>".len();
+        let spectrum: ScanWithData = from_str(&text[start..end]).unwrap();
+        let mut problems = Vec::new();
+        check_arrays(&spectrum, &mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        let mut empty = spectrum.clone();
+        empty.default_array_length = 0;
+        empty.binary_data_array_list.arrays.clear();
+        check_arrays(&empty, &mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        //An arrayLength on each array overrides the spectrum's defaultArrayLength.
+        let mut overridden = spectrum.clone();
+        overridden.default_array_length += 1;
+        for array in overridden.binary_data_array_list.arrays.iter_mut() {
+            array.array_length = Some(spectrum.default_array_length);
+        }
+        check_arrays(&overridden, &mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        let mut wrong = spectrum.clone();
+        wrong.default_array_length += 1;
+        check_arrays(&wrong, &mut problems);
+        assert!(matches!(
+            problems.as_slice(),
+            [ValidationProblem::ArrayLengthMismatch { expected, .. }]
+                if *expected == spectrum.default_array_length + 1
+        ));
+    }
+}
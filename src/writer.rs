@@ -0,0 +1,103 @@
+/*!Writing indexed mzML.
+
+The caller writes the document in order, spectra and chromatograms through `IndexedWriter`
+so their byte offsets are recorded, then `finish` appends the index, the index offset and
+the file checksum.
+*/
+use crate::{MzMLParseError, ScanWithData};
+use quick_xml::escape::escape;
+use sha1::{Digest, Sha1};
+use std::io::Write;
+
+///Passes bytes through to `inner`, keeping count of the bytes written and their SHA-1.
+struct HashingWriter<W: Write> {
+    inner: W,
+    position: u64,
+    hasher: Sha1,
+}
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) struct IndexedWriter<W: Write> {
+    writer: HashingWriter<W>,
+    spectrum_offsets: Vec<(String, u64)>,
+    chromatogram_offsets: Vec<(String, u64)>,
+}
+impl<W: Write> IndexedWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        IndexedWriter {
+            writer: HashingWriter {
+                inner: writer,
+                position: 0,
+                hasher: Sha1::new(),
+            },
+            spectrum_offsets: Vec::new(),
+            chromatogram_offsets: Vec::new(),
+        }
+    }
+    pub(crate) fn write_raw(&mut self, data: &str) -> Result<(), MzMLParseError> {
+        Ok(self.writer.write_all(data.as_bytes())?)
+    }
+    pub(crate) fn write_spectrum(&mut self, spectrum: &ScanWithData) -> Result<(), MzMLParseError> {
+        let xml = quick_xml::se::to_string(spectrum)?;
+        self.write_spectrum_xml(&spectrum.id, &xml)
+    }
+    ///Write a spectrum element already serialized to `xml`.
+    pub(crate) fn write_spectrum_xml(&mut self, id: &str, xml: &str) -> Result<(), MzMLParseError> {
+        self.spectrum_offsets
+            .push((id.to_string(), self.writer.position));
+        self.write_raw(xml)?;
+        self.write_raw("\n")
+    }
+    ///Write a chromatogram element already serialized to `xml`.
+    pub(crate) fn write_chromatogram(&mut self, id: &str, xml: &str) -> Result<(), MzMLParseError> {
+        self.chromatogram_offsets
+            .push((id.to_string(), self.writer.position));
+        self.write_raw(xml)?;
+        self.write_raw("\n")
+    }
+    ///Write the index and checksum, closing the document, and return the inner writer.
+    pub(crate) fn finish(mut self) -> Result<W, MzMLParseError> {
+        let index_list_offset = self.writer.position;
+        let mut index_list = String::from("<indexList count=\"2\">\n");
+        for (name, offsets) in [
+            ("spectrum", &self.spectrum_offsets),
+            ("chromatogram", &self.chromatogram_offsets),
+        ] {
+            index_list.push_str(&format!("<index name=\"{}\">\n", name));
+            for (id, offset) in offsets.iter() {
+                index_list.push_str(&format!(
+                    "<offset idRef=\"{}\">{}</offset>\n",
+                    escape(id),
+                    offset
+                ));
+            }
+            index_list.push_str("</index>\n");
+        }
+        index_list.push_str("</indexList>\n");
+        index_list.push_str(&format!(
+            "<indexListOffset>{}</indexListOffset>\n<fileChecksum>",
+            index_list_offset
+        ));
+        self.write_raw(&index_list)?;
+        let checksum = to_hex(&self.writer.hasher.clone().finalize());
+        self.write_raw(&checksum)?;
+        self.write_raw("</fileChecksum>\n</indexedmzML>\n")?;
+        self.writer.flush()?;
+        Ok(self.writer.inner)
+    }
+}
+
+///Format a digest as lowercase hex, as stored in `<fileChecksum>`.
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}